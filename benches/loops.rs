@@ -0,0 +1,40 @@
+use criterion::{criterion_group, criterion_main, measurement::WallTime, BenchmarkGroup, Criterion};
+use std::io;
+use yarxbi::{evaluator::Interpreter, lexer};
+
+// The program is lexed once up front, so only running it is measured
+fn bench_program(group: &mut BenchmarkGroup<'_, WallTime>, name: &str, source: &str) {
+    let code_lines = lexer::tokenize_program(source).expect("benchmark program should lex");
+    let mut interpreter = Interpreter::new();
+
+    group.bench_function(name, |b| {
+        b.iter(|| {
+            interpreter
+                .run_with_io(&code_lines, &mut io::empty(), &mut io::sink())
+                .expect("benchmark program should run")
+        })
+    });
+}
+
+fn loops(c: &mut Criterion) {
+    let mut group = c.benchmark_group("loops");
+    // Each run takes milliseconds, the default 100 samples would take minutes
+    group.sample_size(10);
+
+    // WEND re-evaluates the condition WHILE compiled, 100k times
+    bench_program(&mut group, "while_100k", "10 LET I = 0\n20 WHILE I < 100000\n30 LET I = I + 1\n40 WEND\n");
+    // GOTO resolved through the precompiled line map, 100k times
+    bench_program(
+        &mut group,
+        "goto_100k",
+        "10 LET I = 0\n20 LET I = I + 1\n30 IF I = 100000 THEN 50\n40 GOTO 20\n50 END\n",
+    );
+
+    // NEXT steps with the limit and step FOR evaluated once, 1M times
+    bench_program(&mut group, "for_1m", "10 FOR I = 1 TO 1000000\n20 NEXT I\n");
+
+    group.finish();
+}
+
+criterion_group!(benches, loops);
+criterion_main!(benches);
@@ -1,5 +1,5 @@
 #!/usr/bin/env yarxbi
 
 10 for i = 0 to 30
-20 print i ; " "
+20 print i ; " ";
 30 next i
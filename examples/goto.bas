@@ -0,0 +1,8 @@
+#!/usr/bin/env yarxbi
+
+10 let i = 0
+20 let i = i + 1
+30 if i < 100000 then 20
+40 goto 60
+50 print "skipped"
+60 print i
@@ -0,0 +1,7 @@
+#!/usr/bin/env yarxbi
+
+10 let s = 0
+20 for i = 1 to 1000000
+30 let s = s + i
+40 next i
+50 print s
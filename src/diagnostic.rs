@@ -0,0 +1,296 @@
+use crate::{lexer, token};
+
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl Severity {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        }
+    }
+}
+
+// `line` is the 0-based physical line in the source file, `column` the 0-based
+// character offset within it when the check can tell
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub line: usize,
+    pub column: Option<u32>,
+    pub severity: Severity,
+    pub message: String,
+    pub code: &'static str,
+}
+
+impl Diagnostic {
+    pub fn to_json(&self) -> String {
+        format!(
+            "{{\"line\":{},\"column\":{},\"severity\":\"{}\",\"message\":\"{}\",\"code\":\"{}\"}}",
+            self.line,
+            match self.column {
+                Some(column) => column.to_string(),
+                None => "null".to_string(),
+            },
+            self.severity.as_str(),
+            escape_json(&self.message),
+            self.code,
+        )
+    }
+}
+
+pub fn to_json(diagnostics: &[Diagnostic]) -> String {
+    let items: Vec<String> = diagnostics.iter().map(|d| d.to_json()).collect();
+    format!("[{}]", items.join(","))
+}
+
+// Lexes the whole program and runs the static checks that don't need execution
+pub fn check_program(source: &str, numbering: lexer::Numbering) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    let mut lines = Vec::new();
+
+    for line in lexer::tokenize_lines(source, numbering) {
+        match line {
+            Ok(line) => lines.push(line),
+            Err(e) => diagnostics.push(Diagnostic {
+                line: e.line,
+                column: Some(e.error.pos),
+                severity: Severity::Error,
+                message: e.error.message,
+                code: "lex-error",
+            }),
+        }
+    }
+
+    let mut seen: HashMap<lexer::LineNumber, usize> = HashMap::new();
+    for (lineno, code) in lines.iter() {
+        if let Some(first) = seen.insert(code.line_number, *lineno) {
+            diagnostics.push(Diagnostic {
+                line: *lineno,
+                column: Some(0),
+                severity: Severity::Error,
+                message: format!(
+                    "Line number {} already used on line {}",
+                    code.line_number.0, first
+                ),
+                code: "duplicate-line",
+            });
+        }
+    }
+
+    for (lineno, code) in lines.iter() {
+        for pair in code.tokens.windows(2) {
+            if let (
+                token::Token::Goto | token::Token::Gosub | token::Token::Then,
+                lexer::TokenAndPos(pos, token::Token::Number(number)),
+            ) = (&pair[0].1, &pair[1])
+            {
+                // A fractional line like 10.5 can't exist, rather than standing for 10.
+                // Without line numbers there is nothing to jump to at all
+                let message = if numbering == lexer::Numbering::Sequential {
+                    "Jumps to line numbers aren't allowed in a program without line numbers".to_string()
+                } else if number.fract() != 0.0 || !seen.contains_key(&lexer::LineNumber(*number as u32)) {
+                    format!("Jump to undefined line {}", number)
+                } else {
+                    continue;
+                };
+                diagnostics.push(Diagnostic {
+                    line: *lineno,
+                    column: Some(*pos),
+                    severity: Severity::Error,
+                    message,
+                    code: "undefined-target",
+                });
+            }
+        }
+    }
+
+    let physical: HashMap<lexer::LineNumber, usize> = lines
+        .iter()
+        .map(|(lineno, code)| (code.line_number, *lineno))
+        .collect();
+    let code_lines: Vec<lexer::LineOfCode> = lines.into_iter().map(|(_, code)| code).collect();
+
+    // Only a warning: pairing by line number can't follow GOTOs, so a NEXT
+    // inside an IF or one reached by jumping past another may still run fine
+    for error in check_nesting(&code_lines) {
+        diagnostics.push(Diagnostic {
+            line: physical[&error.line_number],
+            column: Some(error.pos),
+            severity: Severity::Warning,
+            message: error.message,
+            code: "unbalanced-loop",
+        });
+    }
+
+    diagnostics.sort_by_key(|d| (d.line, d.column));
+    diagnostics
+}
+
+// A loop whose opening and closing statements don't pair up, found by reading
+// the program rather than running it
+#[derive(Debug, Clone, PartialEq)]
+pub struct NestingError {
+    pub line_number: lexer::LineNumber,
+    pub pos: u32,
+    pub message: String,
+}
+
+// Pairs FOR with NEXT and WHILE with WEND in line number order. Loops have to
+// close innermost first, so FOR ... WHILE ... NEXT ... WEND is rejected
+pub fn check_nesting(code_lines: &[lexer::LineOfCode]) -> Vec<NestingError> {
+    let mut errors = Vec::new();
+    // Open loops, innermost last: FOR variable (None for WHILE) and where it started
+    let mut open: Vec<(Option<&str>, lexer::LineNumber, u32)> = Vec::new();
+
+    let describe = |variable: Option<&str>, line_number: lexer::LineNumber| match variable {
+        Some(variable) => format!("FOR {} at line {}", variable, line_number.0),
+        None => format!("WHILE at line {}", line_number.0),
+    };
+
+    for (line_number, tokens) in lexer::lines_in_order(code_lines) {
+        if let Some(lexer::TokenAndPos(_, token::Token::Data)) = tokens.first() {
+            continue;
+        }
+
+        for (index, lexer::TokenAndPos(pos, token)) in tokens.iter().enumerate() {
+            let variable = match tokens.get(index + 1) {
+                Some(lexer::TokenAndPos(_, token::Token::Variable(variable))) => Some(variable.as_str()),
+                _ => None,
+            };
+
+            let (closes, name) = match token {
+                token::Token::For => {
+                    open.push((variable, line_number, *pos));
+                    continue;
+                }
+                token::Token::While => {
+                    open.push((None, line_number, *pos));
+                    continue;
+                }
+                // RESUME NEXT isn't a loop
+                token::Token::Next if index > 0 && tokens[index - 1].1 == token::Token::Resume => continue,
+                token::Token::Next => (variable, "NEXT"),
+                token::Token::Wend => (None, "WEND"),
+                _ => continue,
+            };
+
+            // A bare NEXT closes whichever FOR is innermost
+            let pairs = |loop_variable: Option<&str>| match (name, closes) {
+                ("NEXT", None) => loop_variable.is_some(),
+                _ => loop_variable == closes,
+            };
+
+            let message = match open.last() {
+                Some(&(innermost, _, _)) if pairs(innermost) => {
+                    open.pop();
+                    continue;
+                }
+                Some(&(innermost, start, _)) if open.iter().any(|&(loop_variable, _, _)| pairs(loop_variable)) => {
+                    format!("{} at line {} crosses {}", name, line_number.0, describe(innermost, start))
+                }
+                _ if name == "NEXT" => match closes {
+                    Some(variable) => format!("NEXT {} at line {} without matching FOR", variable, line_number.0),
+                    None => format!("NEXT at line {} without matching FOR", line_number.0),
+                },
+                _ => format!("WEND at line {} without WHILE", line_number.0),
+            };
+
+            errors.push(NestingError {
+                line_number,
+                pos: *pos,
+                message,
+            });
+        }
+    }
+
+    for (variable, line_number, pos) in open {
+        errors.push(NestingError {
+            line_number,
+            pos,
+            message: format!("{} not closed", describe(variable, line_number)),
+        });
+    }
+
+    errors
+}
+
+fn escape_json(s: &str) -> String {
+    let mut escaped = String::new();
+
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nesting_messages(source: &str) -> Vec<String> {
+        let code_lines = lexer::tokenize_program(source).expect("program should lex");
+        check_nesting(&code_lines).into_iter().map(|error| error.message).collect()
+    }
+
+    #[test]
+    fn crossed_loops_are_reported() {
+        let messages = nesting_messages("10 FOR I = 1 TO 2\n20 WHILE TRUE\n30 NEXT I\n40 WEND\n");
+        assert_eq!(messages[0], "NEXT at line 30 crosses WHILE at line 20");
+    }
+
+    #[test]
+    fn unmatched_loops_are_reported() {
+        assert_eq!(nesting_messages("10 NEXT I\n"), ["NEXT I at line 10 without matching FOR"]);
+        assert_eq!(nesting_messages("10 WEND\n"), ["WEND at line 10 without WHILE"]);
+    }
+
+    #[test]
+    fn nesting_is_only_a_warning() {
+        let diagnostics = check_program("10 FOR I = 1 TO 3\n20 IF I < 3 THEN NEXT I\n30 NEXT I\n", lexer::Numbering::Numbered);
+        assert!(!diagnostics.is_empty());
+        assert!(diagnostics.iter().all(|d| d.severity == Severity::Warning));
+    }
+
+    #[test]
+    fn unnumbered_programs_are_checked_as_unnumbered() {
+        assert_eq!(check_program("PRINT 1\nPRINT 2\n", lexer::Numbering::Sequential), []);
+
+        let diagnostics = check_program("PRINT 1\nGOTO 10\n", lexer::Numbering::Sequential);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].line, 1);
+        assert_eq!(diagnostics[0].code, "undefined-target");
+    }
+
+    #[test]
+    fn every_lex_error_is_reported() {
+        let diagnostics = check_program("PRINT 1\n10 PRINT 2\nPRINT 3\n", lexer::Numbering::Numbered);
+        let lines: Vec<usize> = diagnostics.iter().map(|d| d.line).collect();
+        assert_eq!(lines, [0, 2]);
+    }
+
+    #[test]
+    fn json_for_a_lex_error() {
+        let diagnostics = check_program("10 PRINT 1\n20 PRINT \"oops\n", lexer::Numbering::Numbered);
+        assert_eq!(
+            to_json(&diagnostics),
+            "[{\"line\":1,\"column\":9,\"severity\":\"error\",\
+             \"message\":\"Unterminated string, no closing \\\" before the end of the line\",\"code\":\"lex-error\"}]"
+        );
+        assert_eq!(to_json(&[]), "[]");
+    }
+}
@@ -0,0 +1,59 @@
+use std::fmt;
+
+// What went wrong, decided where the error is raised rather than guessed from
+// its message, which may quote the program's own strings
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    NextWithoutFor,
+    ReturnWithoutGosub,
+    OutOfData,
+    IllegalFunctionCall, // Argument outside what a function or statement accepts
+    BadGoto,            // Jump to a line or label that doesn't exist
+    OutOfRange,
+    DivisionByZero,
+    TypeMismatch,
+    ResumeWithoutError,
+    UndefinedVariable,
+    Io,                 // Reading INPUT or writing output failed
+    Limit,              // The run hit its step limit or timeout
+    Syntax,             // Everything else
+}
+
+impl ErrorKind {
+    // Classic BASIC error number, as read by ERR in an ON ERROR handler. Kinds
+    // without a number of their own count as syntax errors
+    pub fn code(&self) -> u32 {
+        match *self {
+            ErrorKind::NextWithoutFor => 1,
+            ErrorKind::ReturnWithoutGosub => 3,
+            ErrorKind::OutOfData => 4,
+            ErrorKind::IllegalFunctionCall => 5,
+            ErrorKind::BadGoto => 8,
+            ErrorKind::OutOfRange => 9,
+            ErrorKind::DivisionByZero => 11,
+            ErrorKind::TypeMismatch => 13,
+            ErrorKind::ResumeWithoutError => 20,
+            ErrorKind::UndefinedVariable | ErrorKind::Io | ErrorKind::Limit | ErrorKind::Syntax => 2,
+        }
+    }
+}
+
+// An error raised by an operation or helper, before the evaluator knows which
+// line and position it belongs to
+#[derive(Debug, Clone, PartialEq)]
+pub struct Error {
+    pub kind: ErrorKind,
+    pub message: String,
+}
+
+impl Error {
+    pub fn new(kind: ErrorKind, message: String) -> Error {
+        Error { kind, message }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
@@ -1,112 +1,966 @@
-use crate::{lexer, token, value};
+use crate::{error::Error, lexer, screen, token, value};
+
+pub use crate::error::ErrorKind;
 
 use std::{
+    cell::{Cell, RefCell},
     collections::{BTreeMap, HashMap, VecDeque},
-    io,
+    convert::TryFrom,
+    fmt,
+    io::{self, BufRead, Read, Write},
     iter::Peekable,
+    process,
     slice::Iter,
+    str::FromStr,
+    time::{Duration, Instant},
 };
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct ForLoop {
     line_no: lexer::LineNumber,
-    pos: u32,
-    slide: bool,
-    stes: bool
+    end: f64,       // Evaluated once by FOR, like the step
+    step: f64,      // Its sign decides whether the loop counts up or down
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct WhileLoop {
     line_no: lexer::LineNumber,
-    pos: u32
+    condition: VecDeque<token::Token>,  // RPN form of the WHILE expression
+}
+
+#[derive(Debug, Clone)]
+struct UserFunction {
+    params: Vec<String>,
+    body: VecDeque<token::Token>,       // RPN form of the expression after =
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct Sub {
     line_no: lexer::LineNumber,
     ret_no: lexer::LineNumber,
 }
 
-#[derive(Debug)]
-struct Subsr {
-    call_no: lexer::LineNumber,
-    sident: String,
-}
-
-#[derive(Debug)]
-struct Context {
+// Runtime state of a program, handed read-only to trace callbacks
+#[derive(Debug, Clone)]
+pub struct Context {
     variables: HashMap<String, value::Value>,   // Variables
     floops: HashMap<String, ForLoop>,           // For loops
+    for_order: Vec<String>,                     // FOR variables, innermost last, for a bare NEXT
     wloops: Vec<WhileLoop>,                     // While loops
     subs: HashMap<String, Sub>,                 // Subroutine definitions
-    subsr: Vec<Subsr>,                          // EIP subroutines
+    functions: HashMap<String, UserFunction>,   // Functions made by DEF FN
+    call_stack: Vec<usize>,                     // Line indices of active GOSUBs
+    scopes: RefCell<Vec<HashMap<String, value::Value>>>, // Locals of active FN calls and GOSUBs, innermost last
+    echo_input: bool,                           // Echo lines read by INPUT
+    input_prompt: bool,                         // Show "? " for an INPUT without a prompt
+    rng_state: Cell<u64>,                       // RND generator state
+    mod_mode: value::ModMode,                   // Sign convention of MOD
+    stopped_at: Option<lexer::LineNumber>,      // Line of the last STOP, for CONT
+    current_line: lexer::LineNumber,            // Line being executed, read by CURLINE
+    data: Vec<(lexer::LineNumber, value::Value)>, // DATA items in program order
+    data_cursor: usize,                         // Next DATA item for READ
+    screen: Option<screen::Screen>,             // Raster set up by SCREEN
+    error_handler: Option<lexer::LineNumber>,   // Target of ON ERROR GOTO
+    error_code: u32,                            // Code of the last trapped error, read by ERR
+    error_line: lexer::LineNumber,              // Line of the last trapped error, read by ERL
+    resume_index: Option<usize>,                // Line index of the trapped error until RESUME
+    precision: Option<usize>,                   // Significant digits shown by PRINT and STR$
+    environment: Option<HashMap<String, String>>, // Variables ENVIRON$ sees instead of the process's
+    allow_shell: bool,                          // Whether SHELL may run commands
+    exit_code: i32,                             // Status of the last SHELL command, read by EXITCODE
+    column: usize,                              // Output column PRINT has reached
+    bool_mode: value::BoolMode,                 // How PRINT shows booleans
+    line_trace: bool,                           // Print [line N] before each line, toggled by TRON and TROFF
+    numbering: lexer::Numbering,                // Sequential programs can't jump to line numbers
+    step_limit: Option<usize>,                  // Most lines a single run may execute
+    timeout: Option<Duration>,                  // Longest a single run may take
 }
 
 impl Context {
+    pub fn variable(&self, name: &str) -> Option<&value::Value> {
+        self.variables.get(name)
+    }
+
+    pub fn current_line(&self) -> lexer::LineNumber {
+        self.current_line
+    }
+
     fn new() -> Context {
         Context {
             variables: HashMap::new(),
             floops: HashMap::new(),
+            for_order: Vec::new(),
             wloops: Vec::new(),
             subs: HashMap::new(),
-            subsr: Vec::new(),
+            functions: HashMap::new(),
+            call_stack: Vec::new(),
+            scopes: RefCell::new(Vec::new()),
+            echo_input: false,
+            input_prompt: false,
+            rng_state: Cell::new(seed_state(DEFAULT_SEED)),
+            mod_mode: value::ModMode::Truncated,
+            stopped_at: None,
+            current_line: lexer::LineNumber(0),
+            data: Vec::new(),
+            data_cursor: 0,
+            screen: None,
+            error_handler: None,
+            error_code: 0,
+            error_line: lexer::LineNumber(0),
+            resume_index: None,
+            precision: None,
+            environment: None,
+            allow_shell: false,
+            exit_code: 0,
+            column: 0,
+            bool_mode: value::BoolMode::Words,
+            line_trace: false,
+            numbering: lexer::Numbering::Numbered,
+            step_limit: None,
+            timeout: None,
+        }
+    }
+
+    fn get_element(&self, name: &str, subscripts: &[value::Value]) -> Result<value::Value, Error> {
+        match self.variables.get(name) {
+            Some(value::Value::Array { dims, elements }) => {
+                let index = array_index(name, dims, subscripts)?;
+                Ok(elements[index].clone())
+            }
+            Some(_) => Err(Error::new(ErrorKind::TypeMismatch, format!("{} is not an array", name))),
+            None => Err(Error::new(ErrorKind::UndefinedVariable, format!("Array {} used before DIM", name))),
+        }
+    }
+
+    // A local of the innermost scope frame hides the global of the same name.
+    // Frames further out can't be seen, so a GOSUB doesn't see its caller's locals
+    fn lookup(&self, name: &str) -> Option<value::Value> {
+        match self.scopes.borrow().last().and_then(|frame| frame.get(name)) {
+            Some(value) => Some(value.clone()),
+            None => self.variables.get(name).cloned(),
+        }
+    }
+
+    // Assigns the local if the innermost frame has one by that name, else the global
+    fn set_variable(&mut self, name: &str, value: value::Value) -> Result<(), Error> {
+        check_sigil(name, &value)?;
+        match self.scopes.get_mut().last_mut() {
+            Some(frame) if frame.contains_key(name) => frame.insert(name.to_string(), value),
+            _ => self.variables.insert(name.to_string(), value),
+        };
+        Ok(())
+    }
+
+    // Every GOSUB gets a frame of its own for LOCAL, dropped again by RETURN
+    fn enter_gosub(&mut self, index: usize) {
+        self.call_stack.push(index);
+        self.scopes.get_mut().push(HashMap::new());
+    }
+
+    fn leave_gosub(&mut self) -> Option<usize> {
+        let index = self.call_stack.pop()?;
+        self.scopes.get_mut().pop();
+        Some(index)
+    }
+
+    // A new run starts outside any loop, GOSUB or error handler, whatever the
+    // previous run was in the middle of when it ended. The locals of a GOSUB
+    // that never returned go with it
+    fn reset_control_flow(&mut self) {
+        self.floops.clear();
+        self.for_order.clear();
+        self.wloops.clear();
+        self.call_stack.clear();
+        self.scopes.get_mut().clear();
+        self.error_handler = None;
+        self.resume_index = None;
+    }
+
+    fn set_element(&mut self, name: &str, subscripts: &[value::Value], value: value::Value) -> Result<(), Error> {
+        check_sigil(name, &value)?;
+        match self.variables.get_mut(name) {
+            Some(value::Value::Array { dims, elements }) => {
+                let index = array_index(name, dims, subscripts)?;
+                elements[index] = value;
+                Ok(())
+            }
+            Some(_) => Err(Error::new(ErrorKind::TypeMismatch, format!("{} is not an array", name))),
+            None => Err(Error::new(ErrorKind::UndefinedVariable, format!("Array {} used before DIM", name))),
+        }
+    }
+
+    // xorshift64*, which is plenty for RND and keeps the state a single u64
+    fn next_random(&self) -> f64 {
+        let mut x = self.rng_state.get();
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.rng_state.set(x);
+
+        (x.wrapping_mul(0x2545_F491_4F6C_DD1D) >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+// A$ only holds strings and A anything but a string, checked on every
+// assignment so a mix-up surfaces there rather than deep in a later expression
+fn check_sigil(name: &str, value: &value::Value) -> Result<(), Error> {
+    match (name.ends_with('$'), value) {
+        (true, value::Value::String(_)) | (false, value::Value::Int(_) | value::Value::Number(_) | value::Value::Bool(_)) => Ok(()),
+        (true, value) => Err(Error::new(
+            ErrorKind::TypeMismatch,
+            format!("Type mismatch, {} can only hold a string but got {}", name, value.describe()),
+        )),
+        (false, value::Value::String(string)) => {
+            Err(Error::new(
+                ErrorKind::TypeMismatch,
+                format!("Type mismatch, {} can't hold string \"{}\", use {}$ for strings", name, string, name),
+            ))
+        }
+        (false, value) => Err(Error::new(
+            ErrorKind::TypeMismatch,
+            format!("Type mismatch, {} can't hold {}", name, value.describe()),
+        )),
+    }
+}
+
+// Flattens subscripts into an index of the row-major element storage
+fn array_index(name: &str, dims: &[usize], subscripts: &[value::Value]) -> Result<usize, Error> {
+    if subscripts.len() != dims.len() {
+        return Err(Error::new(ErrorKind::OutOfRange, format!(
+            "Array {} has {} dimension(s) but got {} subscript(s)",
+            name, dims.len(), subscripts.len()
+        )));
+    }
+
+    let mut index = 0;
+    for (subscript, dim) in subscripts.iter().zip(dims) {
+        let subscript = to_index(subscript)?;
+        if subscript >= *dim {
+            return Err(Error::new(
+                ErrorKind::OutOfRange,
+                format!("Subscript {} out of range for {}, maximum is {}", subscript, name, dim - 1),
+            ));
         }
+        index = index * dim + subscript;
+    }
+
+    Ok(index)
+}
+
+// ANSI sequence CLS writes: erase the display and move the cursor home
+const CLEAR_SCREEN: &str = "\x1b[2J\x1b[H";
+
+// Most elements an array may have, so DIM A(1E12) is an error rather than an
+// attempt to allocate terabytes
+const MAX_ARRAY_ELEMENTS: usize = 1 << 22;
+
+// Deepest FN calls may nest, which also stops a function that calls itself
+const MAX_FN_DEPTH: usize = 100;
+
+// Largest count TAB and SPC take, as in QBasic, so SPC(1E18) is an error rather
+// than a request for an exabyte of spaces
+const MAX_PRINT_SPACING: usize = 32767;
+
+// Columns per zone when PRINT items are separated by commas
+const PRINT_ZONE_WIDTH: usize = 14;
+
+// Lines run between looks at the clock for a timeout
+const TIMEOUT_CHECK_INTERVAL: usize = 1024;
+
+// Without an explicit seed every run produces the same RND sequence
+const DEFAULT_SEED: u64 = 0;
+
+fn seed_state(seed: u64) -> u64 {
+    // The generator must never hold 0
+    match seed ^ 0x9E37_79B9_7F4A_7C15 {
+        0 => 0x9E37_79B9_7F4A_7C15,
+        state => state,
+    }
+}
+
+// Why a program stopped with an error, at which line and position
+#[derive(Debug, Clone, PartialEq)]
+pub struct EvalError {
+    pub line: lexer::LineNumber,
+    pub pos: u32,
+    pub kind: ErrorKind,
+    pub message: String,
+}
+
+impl EvalError {
+    pub fn new(line: lexer::LineNumber, pos: u32, kind: ErrorKind, message: String) -> EvalError {
+        EvalError { line, pos, kind, message }
+    }
+}
+
+impl fmt::Display for EvalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line.0, self.pos, self.message)
     }
 }
 
 macro_rules! err {
-    ($line:ident, $pos:expr, $fmt:expr $(, $p:expr ) *) => {
-        return Err((**$line, $pos, format!($fmt, $($p),*)))
+    ($line:ident, $pos:expr, $kind:expr, $fmt:expr $(, $p:expr ) *) => {
+        return Err(EvalError::new(**$line, $pos, $kind, format!($fmt, $($p),*)))
+    }
+}
+
+// Turns a failed write to the program's output into an evaluation error
+macro_rules! write_output {
+    ($line:ident, $pos:expr, $result:expr) => {
+        if let Err(e) = $result {
+            err!($line, $pos, ErrorKind::Io, "Failed to write output: {}", e);
+        }
+    }
+}
+
+// A copy of the whole runtime state: variables, loops, call stack and where a
+// STOPped program resumes
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    context: Context,
+}
+
+// All interpreter state lives in the Context owned by each Interpreter (there are
+// no statics), so separate interpreters can run side by side, even across threads
+pub struct Interpreter {
+    context: Context,
+    coverage: Option<BTreeMap<lexer::LineNumber, usize>>,
+    trace: Option<Box<TraceCallback>>,
+    input: Option<Box<dyn BufRead + Send>>,     // Where `run` and `cont` read INPUT, stdin when None
+    output: Option<Box<dyn Write + Send>>,      // Where `run` and `cont` print, stdout when None
+}
+
+// Called before each statement with its line and the state it will run against
+pub type TraceCallback = dyn FnMut(lexer::LineNumber, &Context) + Send;
+
+impl fmt::Debug for Interpreter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Interpreter")
+            .field("context", &self.context)
+            .field("coverage", &self.coverage)
+            .field("trace", &self.trace.is_some())
+            .field("input", &self.input.is_some())
+            .field("output", &self.output.is_some())
+            .finish()
+    }
+}
+
+impl Interpreter {
+    pub fn builder() -> InterpreterBuilder {
+        InterpreterBuilder::new()
+    }
+
+    pub fn new() -> Interpreter {
+        Interpreter {
+            context: Context::new(),
+            coverage: None,
+            trace: None,
+            input: None,
+            output: None,
+        }
+    }
+
+    pub fn set_trace<F>(&mut self, callback: F)
+    where
+        F: FnMut(lexer::LineNumber, &Context) + Send + 'static,
+    {
+        self.trace = Some(Box::new(callback));
+    }
+
+    pub fn clear_trace(&mut self) {
+        self.trace = None;
+    }
+
+    // Writes each line consumed by INPUT back to the output, so transcripts of
+    // runs with piped input read like an interactive session
+    pub fn set_echo_input(&mut self, echo: bool) {
+        self.context.echo_input = echo;
+    }
+
+    // Makes an INPUT with no prompt string of its own show "? ", like classic BASIC
+    pub fn set_input_prompt(&mut self, prompt: bool) {
+        self.context.input_prompt = prompt;
+    }
+
+    // Restarts the RND sequence, the same seed always gives the same sequence
+    pub fn set_seed(&mut self, seed: u64) {
+        self.context.rng_state.set(seed_state(seed));
+    }
+
+    // Defaults to truncated, the classic BASIC behaviour
+    pub fn set_mod_mode(&mut self, mode: value::ModMode) {
+        self.context.mod_mode = mode;
+    }
+
+    // Significant digits PRINT and STR$ show, None for value::DEFAULT_DIGITS.
+    // Numbers are rounded to that many digits, so what is shown need not read
+    // back as exactly the same number
+    pub fn set_precision(&mut self, digits: Option<usize>) {
+        self.context.precision = digits;
+    }
+
+    // Limits ENVIRON$ to the given variables rather than the real environment,
+    // for sandboxing and tests
+    pub fn set_environment(&mut self, environment: HashMap<String, String>) {
+        self.context.environment = Some(environment);
+    }
+
+    // SHELL runs arbitrary commands, so it is refused unless allowed here
+    pub fn set_allow_shell(&mut self, allow: bool) {
+        self.context.allow_shell = allow;
+    }
+
+    // Defaults to true/false
+    pub fn set_bool_mode(&mut self, mode: value::BoolMode) {
+        self.context.bool_mode = mode;
+    }
+
+    // Defaults to Numbered. The line numbers of a Sequential program are made up
+    // by the lexer, so GOTO, GOSUB and the like can't target them
+    pub fn set_numbering(&mut self, numbering: lexer::Numbering) {
+        self.context.numbering = numbering;
+    }
+
+    // Stops a run after that many lines with "Execution step limit exceeded",
+    // so untrusted programs can't loop forever. None means no limit
+    pub fn set_step_limit(&mut self, limit: Option<usize>) {
+        self.context.step_limit = limit;
+    }
+
+    // Stops a run that takes longer than this with "Execution timed out". None
+    // means no limit
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.context.timeout = timeout;
+    }
+
+    // Starts the program with TRON in effect
+    pub fn set_line_trace(&mut self, enabled: bool) {
+        self.context.line_trace = enabled;
+    }
+
+    // Counts how often each line runs, see `coverage`
+    pub fn set_coverage(&mut self, enabled: bool) {
+        self.coverage = if enabled { Some(BTreeMap::new()) } else { None };
+    }
+
+    // Execution count of every line of the programs run so far, lines that
+    // never ran are reported with a count of 0
+    pub fn coverage(&self) -> Option<&BTreeMap<lexer::LineNumber, usize>> {
+        self.coverage.as_ref()
+    }
+
+    // Gives `run` and `cont` somewhere other than stdin to read INPUT from. The
+    // reader is kept across runs, so INPUT carries on where the last run stopped
+    pub fn set_input<R>(&mut self, input: R)
+    where
+        R: BufRead + Send + 'static,
+    {
+        self.input = Some(Box::new(input));
+    }
+
+    // Gives `run` and `cont` somewhere other than stdout to print to
+    pub fn set_output<W>(&mut self, output: W)
+    where
+        W: Write + Send + 'static,
+    {
+        self.output = Some(Box::new(output));
+    }
+
+    // Variables are kept between runs on the same interpreter
+    pub fn run(&mut self, code_lines: &[lexer::LineOfCode]) -> Result<String, EvalError> {
+        self.with_output(|interpreter, output| interpreter.run_with_output(code_lines, output))
+    }
+
+    // Like `run`, but everything the program prints goes to `output`
+    pub fn run_with_output(
+        &mut self,
+        code_lines: &[lexer::LineOfCode],
+        output: &mut dyn Write,
+    ) -> Result<String, EvalError> {
+        self.with_input(|interpreter, input| interpreter.run_with_io(code_lines, input, output))
+    }
+
+    // Like `run_with_output`, with INPUT reading its lines from `input`
+    pub fn run_with_io(
+        &mut self,
+        code_lines: &[lexer::LineOfCode],
+        input: &mut dyn BufRead,
+        output: &mut dyn Write,
+    ) -> Result<String, EvalError> {
+        run_program(&mut self.context, self.coverage.as_mut(), self.trace.as_deref_mut(), input, output, code_lines, Entry::Start)
+    }
+
+    // Runs a statement typed at the REPL against the current state. Unlike `run`
+    // it keeps open loops, GOSUBs, the DATA position and where a STOPped program
+    // would CONT from, so the program's variables can be looked at in between
+    pub fn execute_direct(&mut self, code: &lexer::LineOfCode) -> Result<String, EvalError> {
+        self.with_output(|interpreter, output| {
+            interpreter.with_input(|interpreter, input| interpreter.execute_direct_with_io(code, input, output))
+        })
+    }
+
+    pub fn execute_direct_with_io(
+        &mut self,
+        code: &lexer::LineOfCode,
+        input: &mut dyn BufRead,
+        output: &mut dyn Write,
+    ) -> Result<String, EvalError> {
+        let stopped_at = self.context.stopped_at;
+        let result = run_program(
+            &mut self.context,
+            None,
+            self.trace.as_deref_mut(),
+            input,
+            output,
+            std::slice::from_ref(code),
+            Entry::Direct,
+        );
+        self.context.stopped_at = stopped_at;
+        result
+    }
+
+    // Resumes a program after the line it was STOPped on
+    pub fn cont(&mut self, code_lines: &[lexer::LineOfCode]) -> Result<String, EvalError> {
+        self.with_output(|interpreter, output| interpreter.cont_with_output(code_lines, output))
+    }
+
+    pub fn cont_with_output(
+        &mut self,
+        code_lines: &[lexer::LineOfCode],
+        output: &mut dyn Write,
+    ) -> Result<String, EvalError> {
+        self.with_input(|interpreter, input| interpreter.cont_with_io(code_lines, input, output))
     }
+
+    pub fn cont_with_io(
+        &mut self,
+        code_lines: &[lexer::LineOfCode],
+        input: &mut dyn BufRead,
+        output: &mut dyn Write,
+    ) -> Result<String, EvalError> {
+        match self.context.stopped_at {
+            Some(line) => run_program(
+                &mut self.context,
+                self.coverage.as_mut(),
+                self.trace.as_deref_mut(),
+                input,
+                output,
+                code_lines,
+                Entry::Resume(line),
+            ),
+            None => Err(EvalError::new(
+                lexer::LineNumber(0),
+                0,
+                ErrorKind::Syntax,
+                "Cannot continue, program was not stopped".to_string(),
+            )),
+        }
+    }
+
+    // The raster drawn by the program, if it ran SCREEN
+    pub fn screen(&self) -> Option<&screen::Screen> {
+        self.context.screen.as_ref()
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            context: self.context.clone(),
+        }
+    }
+
+    pub fn restore(&mut self, snapshot: &Snapshot) {
+        self.context = snapshot.context.clone();
+    }
+
+    // Calls `run` with the configured input, or stdin. The reader is taken out
+    // for the call so `run` can still borrow the interpreter
+    fn with_input<T>(&mut self, run: impl FnOnce(&mut Interpreter, &mut dyn BufRead) -> T) -> T {
+        let mut input = self.input.take();
+        let result = match input {
+            Some(ref mut input) => run(self, input.as_mut()),
+            None => run(self, &mut io::stdin().lock()),
+        };
+        self.input = input;
+        result
+    }
+
+    // Calls `run` with the configured output, or stdout
+    fn with_output<T>(&mut self, run: impl FnOnce(&mut Interpreter, &mut dyn Write) -> T) -> T {
+        let mut output = self.output.take();
+        let result = match output {
+            Some(ref mut output) => run(self, output.as_mut()),
+            None => run(self, &mut io::stdout()),
+        };
+        self.output = output;
+        result
+    }
+}
+
+impl Default for Interpreter {
+    fn default() -> Interpreter {
+        Interpreter::new()
+    }
+}
+
+// Chainable configuration of a new Interpreter, each option maps onto the
+// setter of the same name
+#[derive(Debug, Default)]
+pub struct InterpreterBuilder {
+    interpreter: Interpreter,
+}
+
+impl InterpreterBuilder {
+    pub fn new() -> InterpreterBuilder {
+        InterpreterBuilder::default()
+    }
+
+    pub fn echo_input(mut self, echo: bool) -> InterpreterBuilder {
+        self.interpreter.set_echo_input(echo);
+        self
+    }
+
+    pub fn input_prompt(mut self, prompt: bool) -> InterpreterBuilder {
+        self.interpreter.set_input_prompt(prompt);
+        self
+    }
+
+    pub fn seed(mut self, seed: u64) -> InterpreterBuilder {
+        self.interpreter.set_seed(seed);
+        self
+    }
+
+    pub fn mod_mode(mut self, mode: value::ModMode) -> InterpreterBuilder {
+        self.interpreter.set_mod_mode(mode);
+        self
+    }
+
+    pub fn precision(mut self, digits: Option<usize>) -> InterpreterBuilder {
+        self.interpreter.set_precision(digits);
+        self
+    }
+
+    pub fn bool_mode(mut self, mode: value::BoolMode) -> InterpreterBuilder {
+        self.interpreter.set_bool_mode(mode);
+        self
+    }
+
+    pub fn environment(mut self, environment: HashMap<String, String>) -> InterpreterBuilder {
+        self.interpreter.set_environment(environment);
+        self
+    }
+
+    pub fn allow_shell(mut self, allow: bool) -> InterpreterBuilder {
+        self.interpreter.set_allow_shell(allow);
+        self
+    }
+
+    pub fn numbering(mut self, numbering: lexer::Numbering) -> InterpreterBuilder {
+        self.interpreter.set_numbering(numbering);
+        self
+    }
+
+    pub fn step_limit(mut self, limit: Option<usize>) -> InterpreterBuilder {
+        self.interpreter.set_step_limit(limit);
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Option<Duration>) -> InterpreterBuilder {
+        self.interpreter.set_timeout(timeout);
+        self
+    }
+
+    pub fn line_trace(mut self, enabled: bool) -> InterpreterBuilder {
+        self.interpreter.set_line_trace(enabled);
+        self
+    }
+
+    pub fn coverage(mut self, enabled: bool) -> InterpreterBuilder {
+        self.interpreter.set_coverage(enabled);
+        self
+    }
+
+    pub fn input<R>(mut self, input: R) -> InterpreterBuilder
+    where
+        R: BufRead + Send + 'static,
+    {
+        self.interpreter.set_input(input);
+        self
+    }
+
+    pub fn output<W>(mut self, output: W) -> InterpreterBuilder
+    where
+        W: Write + Send + 'static,
+    {
+        self.interpreter.set_output(output);
+        self
+    }
+
+    pub fn trace<F>(mut self, callback: F) -> InterpreterBuilder
+    where
+        F: FnMut(lexer::LineNumber, &Context) + Send + 'static,
+    {
+        self.interpreter.set_trace(callback);
+        self
+    }
+
+    pub fn build(self) -> Interpreter {
+        self.interpreter
+    }
+}
+
+pub fn evaluate(code_lines: Vec<lexer::LineOfCode>) -> Result<String, EvalError> {
+    evaluate_with_output(code_lines, &mut io::stdout())
+}
+
+pub fn evaluate_with_output(
+    code_lines: Vec<lexer::LineOfCode>,
+    output: &mut dyn Write,
+) -> Result<String, EvalError> {
+    Interpreter::new().run_with_output(&code_lines, output)
+}
+
+pub fn evaluate_with_io(
+    code_lines: Vec<lexer::LineOfCode>,
+    input: &mut dyn BufRead,
+    output: &mut dyn Write,
+) -> Result<String, EvalError> {
+    Interpreter::new().run_with_io(&code_lines, input, output)
+}
+
+// Position of the first jump in a line that needs a line number: GOTO or GOSUB
+// to anything but a label or SUB, and THEN, ELSE, RESTORE or RESUME with one
+fn line_number_jump(tokens: &[lexer::TokenAndPos]) -> Option<u32> {
+    tokens.iter().enumerate().find_map(|(index, lexer::TokenAndPos(pos, token))| {
+        let next = tokens.get(index + 1).map(|lexer::TokenAndPos(_, next)| next);
+        let jumps = match token {
+            token::Token::Goto => !matches!(next, Some(token::Token::Variable(_))),
+            token::Token::Gosub => !matches!(next, Some(token::Token::Srout(_))),
+            token::Token::Then | token::Token::Else | token::Token::Restore | token::Token::Resume => {
+                matches!(next, Some(token::Token::Number(_)))
+            }
+            _ => false,
+        };
+        if jumps { Some(*pos) } else { None }
+    })
 }
 
-macro_rules! get_variable {
-    ($ctx:ident, $var:expr, $line:ident, $pos:expr) => {
-        match $ctx.variables.get($var) {
-            Some(value) => value,
-            None => err!($line, $pos, "Invalid variable expression {}", $var),
+// The first literal GOTO, THEN or ELSE target in a line that isn't a program
+// line. ON's targets are left alone, a missing one is only an error once picked
+fn missing_jump_target(
+    tokens: &[lexer::TokenAndPos],
+    line_map: &HashMap<lexer::LineNumber, usize>,
+) -> Option<(u32, String)> {
+    if matches!(tokens.first(), Some(lexer::TokenAndPos(_, token::Token::Rem))) {
+        return None;
+    }
+
+    let mut after_on = false;
+    tokens.windows(2).find_map(|pair| {
+        let (lexer::TokenAndPos(_, token), lexer::TokenAndPos(pos, next)) = (&pair[0], &pair[1]);
+        let keyword = match token {
+            token::Token::On => {
+                after_on = true;
+                return None;
+            }
+            token::Token::Goto if !after_on => "GOTO",
+            token::Token::Then | token::Token::Else => "IF",
+            _ => return None,
+        };
+        let number = match next {
+            token::Token::Number(number) => *number,
+            _ => return None,
+        };
+        match to_line_number(number) {
+            Ok(n) if line_map.contains_key(&n) => None,
+            Ok(_) => Some((*pos, format!("Invalid target line for {}", keyword))),
+            Err(e) => Some((*pos, format!("Invalid {} target: {}", keyword, e))),
         }
+    })
+}
+
+// A REM or ' after a statement comments out the rest of its line
+fn without_comment(tokens: &[lexer::TokenAndPos]) -> &[lexer::TokenAndPos] {
+    match tokens.iter().skip(1).position(|lexer::TokenAndPos(_, token)| *token == token::Token::Rem) {
+        Some(index) => &tokens[..index + 1],
+        None => tokens,
     }
 }
 
-pub fn evaluate(code_lines: Vec<lexer::LineOfCode>) -> Result<String, (lexer::LineNumber, u32, String)> {
-    let mut context = Context::new();
+// Where run_program starts, and how much of the state left by the last run it keeps
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Entry {
+    Start,                      // A new run from the first line
+    Resume(lexer::LineNumber),  // CONT, after the line that STOPped
+    Direct,                     // A REPL statement, leaving the program's state be
+}
+
+fn run_program(
+    context: &mut Context,
+    mut coverage: Option<&mut BTreeMap<lexer::LineNumber, usize>>,
+    mut trace: Option<&mut TraceCallback>,
+    input: &mut dyn BufRead,
+    output: &mut dyn Write,
+    code_lines: &[lexer::LineOfCode],
+    entry: Entry,
+) -> Result<String, EvalError> {
+    if context.numbering == lexer::Numbering::Sequential {
+        for line in code_lines.iter() {
+            if let Some(pos) = line_number_jump(&line.tokens) {
+                let message = "Jumps to line numbers aren't allowed in a program without line numbers";
+                return Err(EvalError::new(line.line_number, pos, ErrorKind::Syntax, message.to_string()));
+            }
+        }
+    }
+
     let mut lineno_to_code = BTreeMap::new();
-    let mut line_map = BTreeMap::new();
+    // Where each line number was first used, counting lines from 1 in program order
+    let mut first_use: HashMap<lexer::LineNumber, usize> = HashMap::new();
 
     for (index, line) in code_lines.iter().enumerate() {
-        line_map.insert(&line.line_number, index);
+        // Blank lines lex as an empty line 0 and never clash with anything, but a
+        // line number on its own still counts, it would replace the earlier line
+        if !line.tokens.is_empty() || line.line_number != lexer::LineNumber(0) {
+            if let Some(first) = first_use.insert(line.line_number, index + 1) {
+                return Err(EvalError::new(
+                    line.line_number,
+                    0,
+                    ErrorKind::Syntax,
+                    format!("Line number {} is used twice, by program lines {} and {}", line.line_number.0, first, index + 1),
+                ));
+            }
+        }
         lineno_to_code.insert(&line.line_number, &line.tokens);
     }
 
-    let line_numbers: Vec<_> = line_map.keys().clone().collect();
+    // Jump targets resolve to an index into `line_numbers`, i.e. execution order
+    let line_numbers: Vec<_> = lineno_to_code.keys().collect();
+    let line_map: HashMap<_, _> = line_numbers
+        .iter()
+        .enumerate()
+        .map(|(index, line_number)| (***line_number, index))
+        .collect();
     let num_lines = line_numbers.len();
-    let mut line_index = 0;
+
+    // Each line's statement by index, so the main loop doesn't look it up again
+    let statements: Vec<_> = line_numbers
+        .iter()
+        .map(|line_number| without_comment(lineno_to_code[*line_number]))
+        .collect();
+
+    // A literal GOTO or THEN/ELSE target that doesn't exist is reported before
+    // the program starts rather than when the jump is reached
+    for (line_number, tokens) in line_numbers.iter().zip(statements.iter()) {
+        if let Some((pos, message)) = missing_jump_target(tokens, &line_map) {
+            return Err(EvalError::new(***line_number, pos, ErrorKind::BadGoto, message));
+        }
+    }
+
+    // WHILE and WEND lines paired up once, rather than scanning for the partner
+    // on every pass of the loop
+    let mut loop_partners = HashMap::new();
+    for (index, tokens) in statements.iter().enumerate() {
+        let partner = match while_depth(tokens) {
+            0 => None,
+            depth if depth > 0 => matching_wend(&lineno_to_code, &line_numbers, index),
+            _ => matching_while(&lineno_to_code, &line_numbers, index),
+        };
+        if let Some(partner) = partner {
+            loop_partners.insert(index, partner);
+        }
+    }
+
+    let mut labels = HashMap::new();
+    for (index, line_number) in line_numbers.iter().enumerate() {
+        if let Some(lexer::TokenAndPos(pos, token::Token::Label(label))) = lineno_to_code[*line_number].first() {
+            if let Some(first) = labels.insert(label.clone(), index) {
+                return Err(EvalError::new(
+                    ***line_number,
+                    *pos,
+                    ErrorKind::Syntax,
+                    format!("Label {} is already defined on line {}", label, line_numbers[first].0),
+                ));
+            }
+        }
+    }
+
+    if let Some(ref mut coverage) = coverage {
+        for line_number in line_numbers.iter() {
+            coverage.entry(***line_number).or_insert(0);
+        }
+    }
+    let mut line_index = match entry {
+        Entry::Resume(line) => line_numbers.iter().position(|n| ***n > line).unwrap_or(num_lines),
+        Entry::Start | Entry::Direct => 0,
+    };
+
+    // DATA is gathered up front so READ can reach items on lines not yet run. A
+    // direct statement READs on through the program's DATA instead
+    if entry != Entry::Direct {
+        context.data = collect_data(&lineno_to_code)?;
+    }
+    if entry == Entry::Start {
+        context.data_cursor = 0;
+        context.reset_control_flow();
+    }
+
+    context.stopped_at = None;
     // TODO: Feels hacky
-    let mut line_has_goto = false;
+    let mut line_has_goto;
+    let mut should_halt = false;
 
     let mut is_isub: Option<(String, lexer::LineNumber)> = None;
+    let mut steps = 0;
+    let started = Instant::now();
 
-    loop {
+    while line_index < num_lines {
             let line_number = line_numbers[line_index];
-            let tokens = &lineno_to_code[line_number];
+
+            // Neither limit is trappable by ON ERROR, the handler could loop just the same
+            steps += 1;
+            if context.step_limit.is_some_and(|limit| steps > limit) {
+                return Err(EvalError::new(**line_number, 0, ErrorKind::Limit, "Execution step limit exceeded".to_string()));
+            }
+            if steps % TIMEOUT_CHECK_INTERVAL == 0 && context.timeout.is_some_and(|timeout| started.elapsed() > timeout) {
+                return Err(EvalError::new(**line_number, 0, ErrorKind::Limit, "Execution timed out".to_string()));
+            }
+
+            let tokens = statements[line_index];
             let mut token_iter = tokens.iter().peekable();
 
-            // println!("Looking at line: {:?}", line_number);
-            
+            // Set default value
+            line_has_goto = false;
+            context.current_line = **line_number;
+
             if !tokens.is_empty() {
                 let lexer::TokenAndPos(pos, ref token) = *token_iter.next().unwrap();
-                // Set default value
-                line_has_goto = false;
 
-                if is_isub == None || *token == token::Token::Return {
+                if is_isub.is_none() || *token == token::Token::Return {
+
+                if let Some(ref mut coverage) = coverage {
+                    *coverage.entry(**line_number).or_insert(0) += 1;
+                }
+                if let Some(ref mut trace) = trace {
+                    trace(**line_number, context);
+                }
+                if context.line_trace {
+                    let text = format!("[line {}]", line_number.0);
+                    write_output!(line_number, pos, print_text(context, output, &text));
+                }
+
+                let error_index = line_index;
 
-                match evaluate_com(&mut context,
+                match evaluate_com(context,
+                            input,
+                            output,
                             &lineno_to_code,
+                            &loop_partners,
                             &line_map,
+                            &labels,
                             &line_numbers,
                             &mut line_index,
                             &mut line_has_goto,
+                            &mut should_halt,
                             &mut is_isub,
                             token_iter,
                             line_number,
@@ -114,151 +968,615 @@ pub fn evaluate(code_lines: Vec<lexer::LineOfCode>) -> Result<String, (lexer::Li
                             token,
                 ) {
                     Ok(_) => {},
-                    Err(e) => return Err(e),
+                    // Errors inside the handler itself can't be trapped
+                    Err(e) => match context.error_handler.and_then(|handler| line_map.get(&handler)) {
+                        Some(&handler_index) if context.resume_index.is_none() => {
+                            context.error_code = e.kind.code();
+                            context.error_line = e.line;
+                            context.resume_index = Some(error_index);
+                            line_index = handler_index;
+                            line_has_goto = true;
+                        }
+                        _ => return Err(e),
+                    },
                 }};
             }
 
+        if should_halt {
+            break;
+        }
+
+        // A jump has already set `line_index` to the line to run next; anything
+        // else steps forward, and stepping past the last line ends the program
         if !line_has_goto {
             line_index += 1;
-            if line_index == num_lines {
-                break;
-            }
         }
     }
 
-    Ok("\nExecuted successfully".to_string())
+    if let Err(e) = output.flush() {
+        return Err(EvalError::new(context.current_line, 0, ErrorKind::Io, format!("Failed to write output: {}", e)));
+    }
+
+    // Only break the line if the last PRINT left the cursor on it
+    let separator = if context.column > 0 { "\n" } else { "" };
+    Ok(format!("{}Executed successfully", separator))
 }
 
+#[allow(clippy::too_many_arguments)]
 fn evaluate_com(
     context: &mut Context,
+    input: &mut dyn BufRead,
+    output: &mut dyn Write,
     lineno_to_code: &BTreeMap<&lexer::LineNumber, &Vec<lexer::TokenAndPos>>,
-    line_map: &BTreeMap<&lexer::LineNumber, usize>,
-    line_numbers: &Vec<&&lexer::LineNumber>,
+    loop_partners: &HashMap<usize, usize>,
+    line_map: &HashMap<lexer::LineNumber, usize>,
+    labels: &HashMap<String, usize>,
+    line_numbers: &[&&lexer::LineNumber],
     line_index: &mut usize,
     line_has_goto: &mut bool,
+    should_halt: &mut bool,
     is_isub: &mut Option<(String, lexer::LineNumber)>,
     mut token_iter: Peekable<Iter<'_, lexer::TokenAndPos>>,
     line_number: &&lexer::LineNumber,
     pos: u32,
     token: &token::Token,
-) -> Result<String, (lexer::LineNumber, u32, String)> {
+) -> Result<String, EvalError> {
 
     match *token {
-        token::Token::Rem => {},
+        token::Token::Rem | token::Token::Data => {},
+
+        token::Token::End => *should_halt = true,
+
+        // Only the terminal is cleared, not a SCREEN raster
+        token::Token::Cls => {
+            write_output!(line_number, pos, output.write_all(CLEAR_SCREEN.as_bytes()));
+            context.column = 0;
+        }
+
+        token::Token::Tron => context.line_trace = true,
+
+        token::Token::Troff => context.line_trace = false,
+
+        // A label only marks the line, whatever follows it is run as usual
+        token::Token::Label(_) => {
+            if let Some(lexer::TokenAndPos(lpos, ltoken)) = token_iter.next() {
+                return evaluate_com(
+                    context,
+                    input,
+                    output,
+                    lineno_to_code,
+                    loop_partners,
+                    line_map,
+                    labels,
+                    line_numbers,
+                    line_index,
+                    line_has_goto,
+                    should_halt,
+                    is_isub,
+                    token_iter,
+                    line_number,
+                    *lpos,
+                    ltoken,
+                );
+            }
+        }
+
+        token::Token::Stop => {
+            let text = format!("\nBreak in {}\n", line_number.0);
+            write_output!(line_number, pos, print_text(context, output, &text));
+            context.stopped_at = Some(**line_number);
+            *should_halt = true;
+        }
 
         token::Token::Goto => {
             *line_has_goto = true;
             match token_iter.next() {
                 Some(&lexer::TokenAndPos(pos, token::Token::Number(number))) => {
-                    let n = lexer::LineNumber(number as u32);
+                    let n = match to_line_number(number) {
+                        Ok(n) => n,
+                        Err(e) => err!(line_number, pos, e.kind, "Invalid GOTO target: {}", e),
+                    };
                     match line_map.get(&n) {
                         Some(index) => *line_index = *index,
-                        _ => err!(line_number, pos, "Invalid target line for GOTO")
+                        _ => err!(line_number, pos, ErrorKind::BadGoto, "Invalid target line for GOTO")
                     }
                 }
+
+                Some(&lexer::TokenAndPos(pos, token::Token::Variable(ref label))) => match labels.get(label) {
+                    Some(index) => *line_index = *index,
+                    None => err!(line_number, pos, ErrorKind::BadGoto, "GOTO to undefined label {}", label),
+                },
                 
-                Some(&lexer::TokenAndPos(pos, _)) => err!(line_number, pos, "GOTO must be followed by a valid line number"),
+                Some(&lexer::TokenAndPos(pos, _)) => err!(line_number, pos, ErrorKind::Syntax, "GOTO must be followed by a valid line number or label"),
                 
-                None => err!(line_number, pos + 4, "GOTO must be followed by a line number"),
+                None => err!(line_number, pos + 4, ErrorKind::Syntax, "GOTO must be followed by a line number or label"),
             }
         }
 
-        token::Token::Let => {
-            // Expected Next:
+        token::Token::Let if matches!(
+            token_iter.peek(),
+            Some(&&lexer::TokenAndPos(_, token::Token::Function(ref name))) if name == "MID$"
+        ) => {
+            token_iter.next();
+            if let Err(e) = assign_mid(&mut token_iter, context, input) {
+                err!(line_number, pos, e.kind, "{}", e);
+            }
+        }
+
+        token::Token::Function(ref name) if name == "MID$" => {
+            if let Err(e) = assign_mid(&mut token_iter, context, input) {
+                err!(line_number, pos, e.kind, "{}", e);
+            }
+        }
+
+        token::Token::Let => {
+            // Expected Next:
             // Variable Equals EXPRESSION
-            match (
-                token_iter.next(),
-                token_iter.next(),
-                parse_and_eval_expression(&mut token_iter, &context),
-            ) {
+            // Variable LParen EXPRESSION RParen Equals EXPRESSION
+            let variable = match token_iter.next() {
+                Some(&lexer::TokenAndPos(_, token::Token::Variable(ref variable))) => variable,
+                _ => err!(line_number, pos, ErrorKind::Syntax, "Invalid syntax for LET"),
+            };
+
+            let subscripts = match token_iter.peek() {
+                Some(&&lexer::TokenAndPos(_, token::Token::LParen)) => {
+                    match parse_and_eval_subscripts(&mut token_iter, context, input) {
+                        Ok(subscripts) => Some(subscripts),
+                        Err(e) => err!(line_number, pos, e.kind, "Error in LET subscript: {}", e),
+                    }
+                }
+                _ => None,
+            };
+
+            match token_iter.next() {
+                Some(&lexer::TokenAndPos(_, token::Token::Equals)) => {}
+                _ => err!(line_number, pos, ErrorKind::Syntax, "Invalid syntax for LET"),
+            }
+
+            let value = match parse_and_eval_expression(&mut token_iter, context, input) {
+                Ok(value) => value,
+                Err(e) => err!(line_number, pos, e.kind, "Error in LET expression: {}", e),
+            };
+
+            match subscripts {
+                Some(subscripts) => {
+                    if let Err(e) = context.set_element(variable, &subscripts, value) {
+                        err!(line_number, pos, e.kind, "{}", e);
+                    }
+                }
+                None => {
+                    if let Err(e) = context.set_variable(variable, value) {
+                        err!(line_number, pos, e.kind, "{}", e);
+                    }
+                }
+            }
+        }
+
+        token::Token::Local => {
+            // Expected Next:
+            // Variable [Comma Variable]...
+            loop {
+                let variable = match token_iter.next() {
+                    Some(&lexer::TokenAndPos(_, token::Token::Variable(ref variable))) => variable,
+                    _ => err!(line_number, pos, ErrorKind::Syntax, "LOCAL must be followed by variable names"),
+                };
+                let initial = if variable.ends_with('$') {
+                    value::Value::String(String::new())
+                } else {
+                    value::Value::Int(0)
+                };
+
+                // Starts afresh even if the same GOSUB already declared it
+                match context.scopes.get_mut().last_mut() {
+                    Some(frame) => frame.insert(variable.clone(), initial),
+                    None => err!(line_number, pos, ErrorKind::Syntax, "LOCAL can only be used inside a GOSUB"),
+                };
+
+                match token_iter.next() {
+                    Some(&lexer::TokenAndPos(_, token::Token::Comma)) => continue,
+                    None => break,
+                    _ => err!(line_number, pos, ErrorKind::Syntax, "Invalid syntax for LOCAL"),
+                }
+            }
+        }
+
+        token::Token::Def => {
+            // Expected Next:
+            // Fn Variable LParen [Variable [Comma Variable]...] RParen Equals EXPRESSION
+            let name = match (token_iter.next(), token_iter.next(), token_iter.next()) {
                 (
-                    Some(&lexer::TokenAndPos(_, token::Token::Variable(ref variable))),
-                    Some(&lexer::TokenAndPos(_, token::Token::Equals)),
-                    Ok(ref value),
-                ) => {
-                    context
-                        .variables
-                        .insert(variable.to_string(), value.clone());
+                    Some(&lexer::TokenAndPos(_, token::Token::Fn)),
+                    Some(&lexer::TokenAndPos(_, token::Token::Variable(ref name))),
+                    Some(&lexer::TokenAndPos(_, token::Token::LParen)),
+                ) => name,
+                _ => err!(line_number, pos, ErrorKind::Syntax, "DEF must be followed by FN, a function name and ("),
+            };
+
+            let mut params: Vec<String> = Vec::new();
+            loop {
+                match token_iter.next() {
+                    Some(&lexer::TokenAndPos(_, token::Token::RParen)) if params.is_empty() => break,
+                    Some(&lexer::TokenAndPos(ppos, token::Token::Variable(ref param))) => {
+                        if params.contains(param) {
+                            err!(line_number, ppos, ErrorKind::Syntax, "Parameter {} appears twice in DEF FN {}", param, name);
+                        }
+                        params.push(param.clone());
+                    }
+                    _ => err!(line_number, pos, ErrorKind::Syntax, "Invalid parameter list for DEF FN {}", name),
                 }
 
-                (_, _, Err(e)) => err!(line_number, pos, "Error in LET expression: {}", e),
+                match token_iter.next() {
+                    Some(&lexer::TokenAndPos(_, token::Token::Comma)) => continue,
+                    Some(&lexer::TokenAndPos(_, token::Token::RParen)) => break,
+                    _ => err!(line_number, pos, ErrorKind::Syntax, "Invalid parameter list for DEF FN {}", name),
+                }
+            }
 
-                _ => err!(line_number, pos, "Invalid syntax for LET"),
-                
+            match token_iter.next() {
+                Some(&lexer::TokenAndPos(_, token::Token::Equals)) => {}
+                _ => err!(line_number, pos, ErrorKind::Syntax, "DEF FN {} must be followed by = and an expression", name),
+            }
+
+            // Compiled once here, a redefinition replaces the function
+            let body = match parse_expression(&mut token_iter) {
+                Ok(body) if token_iter.peek().is_none() => body,
+                Ok(_) => err!(line_number, pos, ErrorKind::Syntax, "Unexpected tokens after DEF FN {} expression", name),
+                Err(e) => err!(line_number, pos, e.kind, "Error in DEF FN {} expression: {}", name, e),
+            };
+            context.functions.insert(name.clone(), UserFunction { params, body });
+        }
+
+        token::Token::Swap => {
+            // Expected Next:
+            // Variable Comma Variable
+            let (first, second) = match (token_iter.next(), token_iter.next(), token_iter.next(), token_iter.next()) {
+                (
+                    Some(&lexer::TokenAndPos(_, token::Token::Variable(ref first))),
+                    Some(&lexer::TokenAndPos(_, token::Token::Comma)),
+                    Some(&lexer::TokenAndPos(_, token::Token::Variable(ref second))),
+                    None,
+                ) => (first, second),
+                _ => err!(line_number, pos, ErrorKind::Syntax, "SWAP must be followed by two variables separated by a comma"),
+            };
+
+            let (first_value, second_value) = match (context.lookup(first), context.lookup(second)) {
+                (Some(first_value), Some(second_value)) => (first_value, second_value),
+                (None, _) => err!(line_number, pos, ErrorKind::UndefinedVariable, "Invalid variable {} in SWAP", first),
+                (_, None) => err!(line_number, pos, ErrorKind::UndefinedVariable, "Invalid variable {} in SWAP", second),
+            };
+
+            // As in classic BASIC both have to be string variables or both numeric
+            // ones, A$ can't take the number in A. Numeric variables swap whatever
+            // they hold, so an integer and a fraction or a boolean trade places.
+            // The check comes before either changes, so a failed SWAP leaves both alone
+            if first.ends_with('$') != second.ends_with('$') {
+                err!(line_number, pos, ErrorKind::TypeMismatch,
+                    "Type mismatch, SWAP needs two string or two numeric variables, got {} and {}", first, second);
+            }
+            // Whole arrays don't swap, only plain variables
+            for (name, value) in [(first, &first_value), (second, &second_value)] {
+                if let value::Value::Array { .. } = value {
+                    err!(line_number, pos, ErrorKind::TypeMismatch, "Type mismatch, {} is an array and can't be swapped", name);
+                }
+            }
+            if let Err(e) = context.set_variable(first, second_value) {
+                err!(line_number, pos, e.kind, "{}", e);
+            }
+            if let Err(e) = context.set_variable(second, first_value) {
+                err!(line_number, pos, e.kind, "{}", e);
+            }
+        }
+
+        token::Token::Dim => {
+            // Expected Next:
+            // Variable LParen EXPRESSION RParen [Comma ...]
+            loop {
+                let variable = match token_iter.next() {
+                    Some(&lexer::TokenAndPos(_, token::Token::Variable(ref variable))) => variable,
+                    _ => err!(line_number, pos, ErrorKind::Syntax, "DIM must be followed by an array name"),
+                };
+
+                let sizes = match parse_and_eval_subscripts(&mut token_iter, context, input) {
+                    Ok(subscripts) => match subscripts.iter().map(to_index).collect::<Result<Vec<_>, _>>() {
+                        Ok(sizes) => sizes,
+                        Err(e) => err!(line_number, pos, e.kind, "Invalid size for DIM: {}", e),
+                    },
+                    Err(e) => err!(line_number, pos, e.kind, "Error in DIM size: {}", e),
+                };
+
+                let initial = if variable.ends_with('$') {
+                    value::Value::String(String::new())
+                } else {
+                    value::Value::Int(0)
+                };
+                // Like classic BASIC, DIM A(10) makes A(0) to A(10) usable. Checked, as
+                // the dimensions of DIM A(1E9, 1E9) multiply past usize
+                let dims: Vec<usize> = sizes.iter().map(|size| size.saturating_add(1)).collect();
+                let count = match dims.iter().try_fold(1usize, |count, &dim| count.checked_mul(dim)) {
+                    Some(count) if count <= MAX_ARRAY_ELEMENTS => count,
+                    _ => err!(line_number, pos, ErrorKind::IllegalFunctionCall, "DIM {} is too large, an array holds at most {} elements",
+                        variable, MAX_ARRAY_ELEMENTS),
+                };
+                let mut elements = vec![initial; count];
+
+                // Optional initial values: DIM A(2) = {1, 2, 3}
+                if let Some(&&lexer::TokenAndPos(_, token::Token::Equals)) = token_iter.peek() {
+                    token_iter.next();
+
+                    let values = match parse_and_eval_list(&mut token_iter, context, input) {
+                        Ok(values) => values,
+                        Err(e) => err!(line_number, pos, e.kind, "Error in DIM initial values: {}", e),
+                    };
+
+                    if values.len() != elements.len() {
+                        let sizes: Vec<String> = sizes.iter().map(|size| size.to_string()).collect();
+                        err!(line_number, pos, ErrorKind::Syntax, "DIM {}({}) expects {} initial values, got {}",
+                            variable, sizes.join(","), elements.len(), values.len());
+                    }
+
+                    // Each value goes through the same check as an assignment to A(i)
+                    if let Err(e) = values.iter().try_for_each(|value| check_sigil(variable, value)) {
+                        err!(line_number, pos, e.kind, "Error in DIM initial values: {}", e);
+                    }
+
+                    elements = values;
+                }
+
+                context.variables.insert(variable.to_string(), value::Value::Array { dims, elements });
+
+                match token_iter.next() {
+                    Some(&lexer::TokenAndPos(_, token::Token::Comma)) => continue,
+                    None => break,
+                    _ => err!(line_number, pos, ErrorKind::Syntax, "Invalid syntax for DIM"),
+                }
             }
         }
 
         token::Token::Print => {
             // Expected Next:
-            // EXPRESSION
-            match parse_and_eval_expression(&mut token_iter, &context) {
-                Ok(value::Value::String(value)) => print!("{}", value),
-                Ok(value::Value::Number(value)) => print!("{}", value),
-                Ok(value::Value::Bool(value)) => print!("{}", value),
-                Err(_) => err!(line_number, pos, "PRINT must be followed by valid expression"),
+            // [EXPRESSION [(Semicolon | Comma) EXPRESSION]... [Semicolon | Comma]]
+            // A bare PRINT just ends the line
+            if token_iter.peek().is_none() {
+                write_output!(line_number, pos, print_text(context, output, "\n"));
+                return Ok(String::new());
             }
 
-            while let Some(&&lexer::TokenAndPos(_, token::Token::Semicolon)) = token_iter.peek()
-            {
-                token_iter.next();
+            loop {
+                let text = match token_iter.peek() {
+                    // TAB(n) moves to column n, counting from 1, and SPC(n) prints n spaces.
+                    // A TAB to a column the cursor is already past can't go back, so it
+                    // ends the line and goes to that column on the next one:
+                    // PRINT "HELLO"; TAB(3); "X" prints HELLO, then "  X" below it
+                    Some(&&lexer::TokenAndPos(tpos, ref spacing @ (token::Token::Tab | token::Token::Spc))) => {
+                        token_iter.next();
+                        let count = match (
+                            token_iter.next(),
+                            parse_and_eval_expression(&mut token_iter, context, input).map(value::Value::into_float),
+                            token_iter.next(),
+                        ) {
+                            (
+                                Some(&lexer::TokenAndPos(_, token::Token::LParen)),
+                                Ok(value::Value::Number(count)),
+                                Some(&lexer::TokenAndPos(_, token::Token::RParen)),
+                            ) => to_count(count),
+                            _ => err!(line_number, tpos, ErrorKind::Syntax, "TAB and SPC must be followed by a number in parentheses"),
+                        };
+                        if count > MAX_PRINT_SPACING {
+                            err!(line_number, tpos, ErrorKind::IllegalFunctionCall, "TAB and SPC go up to {}, got {}",
+                                MAX_PRINT_SPACING, count);
+                        }
+
+                        match *spacing {
+                            token::Token::Tab => {
+                                let target = count.saturating_sub(1);
+                                if target < context.column {
+                                    format!("\n{}", " ".repeat(target))
+                                } else {
+                                    " ".repeat(target - context.column)
+                                }
+                            }
+                            _ => " ".repeat(count),
+                        }
+                    }
+                    _ => match parse_and_eval_expression(&mut token_iter, context, input) {
+                        Ok(value::Value::Array { .. }) => err!(line_number, pos, ErrorKind::TypeMismatch, "Cannot PRINT a whole array"),
+                        Ok(value) => format_value(&value, context),
+                        Err(e) => err!(line_number, pos, e.kind, "Error in PRINT expression: {}", e),
+                    },
+                };
+                write_output!(line_number, pos, print_text(context, output, &text));
+
+                match token_iter.next() {
+                    Some(&lexer::TokenAndPos(_, token::Token::Semicolon)) => {}
+                    // A comma moves on to the start of the next print zone
+                    Some(&lexer::TokenAndPos(_, token::Token::Comma)) => {
+                        let zone_start = (context.column / PRINT_ZONE_WIDTH + 1) * PRINT_ZONE_WIDTH;
+                        let padding = " ".repeat(zone_start - context.column);
+                        write_output!(line_number, pos, print_text(context, output, &padding));
+                    }
+                    None | Some(&lexer::TokenAndPos(_, token::Token::Rem)) => {
+                        write_output!(line_number, pos, print_text(context, output, "\n"));
+                        break;
+                    }
+                    Some(&lexer::TokenAndPos(tpos, _)) => err!(line_number, tpos, ErrorKind::Syntax, "Expected ; or , between PRINT items"),
+                }
 
-                match parse_and_eval_expression(&mut token_iter, &context) {
-                    Ok(value::Value::String(value)) => print!("{}", value),
-                    Ok(value::Value::Number(value)) => print!("{}", value),
-                    Ok(value::Value::Bool(value)) => print!("{}", value),
-                    Err(_) => err!(line_number, pos, "PRINT must be followed by valid expression"),
+                // A trailing separator leaves the cursor on the same line for the next PRINT
+                if token_iter.peek().is_none() {
+                    break;
                 }
             }
         }
 
         token::Token::Input => {
-            match token_iter.next() {
-                Some(&lexer::TokenAndPos(_, token::Token::Variable(ref variable))) => {
-                    let mut input = String::new();
-
-                    io::stdin()
-                        .read_line(&mut input)
-                        .expect("failed to read line");
-                    input = input.trim().to_string();
-                    let value = value::Value::String(input);
+            // Expected Next:
+            // [BString Semicolon] Variable [Comma Variable]...
+            let prompt = match token_iter.peek() {
+                Some(&&lexer::TokenAndPos(_, token::Token::BString(ref prompt))) => {
+                    token_iter.next();
+                    match token_iter.next() {
+                        Some(&lexer::TokenAndPos(_, token::Token::Semicolon)) => Some(prompt.as_str()),
+                        _ => err!(line_number, pos, ErrorKind::Syntax, "INPUT prompt must be followed by ;"),
+                    }
+                }
+                _ if context.input_prompt => Some("? "),
+                _ => None,
+            };
 
-                    // Store the string now, can coerce to number later if needed
-                    // Can overwrite an existing value
-                    context
-                        .variables
-                        .entry(variable.to_string())
-                        .or_insert(value);
+            let mut variables = Vec::new();
+            loop {
+                match token_iter.next() {
+                    Some(&lexer::TokenAndPos(_, token::Token::Variable(ref variable))) => variables.push(variable),
+                    _ => err!(line_number, pos + 5, ErrorKind::Syntax, "INPUT must be followed by a variable name"),
                 }
+                match token_iter.next() {
+                    Some(&lexer::TokenAndPos(_, token::Token::Comma)) => continue,
+                    None => break,
+                    Some(&lexer::TokenAndPos(tpos, _)) => err!(line_number, tpos, ErrorKind::Syntax, "Expected , between INPUT variables"),
+                }
+            }
+
+            // The prompt has no newline, so it must be flushed to be seen
+            if let Some(prompt) = prompt {
+                write_output!(line_number, pos, print_text(context, output, prompt));
+                write_output!(line_number, pos, output.flush());
+            }
+
+            let mut line = String::new();
+
+            match input.read_line(&mut line) {
+                Ok(0) => err!(line_number, pos, ErrorKind::Io, "Unexpected end of input"),
+                Ok(_) => {}
+                Err(e) => err!(line_number, pos, ErrorKind::Io, "Failed to read input: {}", e),
+            }
+            let line = line.trim().to_string();
+
+            if context.echo_input {
+                let text = format!("{}\n", line);
+                write_output!(line_number, pos, print_text(context, output, &text));
+            } else {
+                // Whoever typed the line ended it with Enter
+                context.column = 0;
+            }
+
+            // A lone variable takes the whole line, commas and all
+            let fields: Vec<String> = if variables.len() == 1 {
+                vec![line]
+            } else {
+                line.split(',').map(|field| field.trim().to_string()).collect()
+            };
+            if fields.len() < variables.len() {
+                err!(line_number, pos, ErrorKind::Syntax, "Not enough values for INPUT, expected {} but got {}", variables.len(), fields.len());
+            }
+            if fields.len() > variables.len() {
+                err!(line_number, pos, ErrorKind::Syntax, "Extra input ignored, INPUT expected {} values but got {}", variables.len(), fields.len());
+            }
 
-                _ => err!(line_number, pos + 5, "INPUT must be followed by a variable name"),
+            // Can overwrite an existing value
+            for (variable, field) in variables.into_iter().zip(fields) {
+                let value = if variable.ends_with('$') {
+                    value::Value::String(field)
+                } else {
+                    match f64::from_str(field.trim()) {
+                        Ok(number) => value::Value::from_number(number),
+                        Err(_) => err!(line_number, pos, ErrorKind::TypeMismatch, "INPUT expected a number for {}, got \"{}\"", variable, field),
+                    }
+                };
+                if let Err(e) = context.set_variable(variable, value) {
+                    err!(line_number, pos, e.kind, "{}", e);
+                }
             }
         }
 
         token::Token::If => {
             // Expected Next:
-            // EXPRESSION Then Number
-            // Where Number is a Line Number
-            match (
-                parse_and_eval_expression(&mut token_iter, &context),
-                token_iter.next(),
+            // CONDITION Then Number
+            // CONDITION Then Variable
+            // CONDITION Then STATEMENT
+            // Where Number is a Line Number and Variable a label
+            let truth = match (
+                parse_and_eval_condition(&mut token_iter, context, input),
                 token_iter.next(),
             ) {
                 (
-                    Ok(value::Value::Bool(ref value)),
+                    Ok(value::Value::Bool(value)),
                     Some(&lexer::TokenAndPos(_, token::Token::Then)),
-                    Some(&lexer::TokenAndPos(_, token::Token::Number(ref number))),
-                ) => {
-                        if *value {
-                            *line_has_goto = true;
-                            let n = lexer::LineNumber(*number as u32);
-                            match line_map.get(&n) {
-                                Some(index) => *line_index = *index,
-                                _ => err!(line_number, pos, "Invalid target line for IF"),
-                                }
-                            }
+                ) => value,
+
+                (Err(e), _) => err!(line_number, pos, e.kind, "Error in IF condition: {}", e),
+                _ => err!(line_number, pos, ErrorKind::Syntax, "Invalid syntax for IF"),
+            };
+
+            // Split off the ELSE branch; nested IFs on the line claim the ELSEs after
+            // them first, so an ELSE always pairs with the nearest IF
+            let rest: Vec<lexer::TokenAndPos> = token_iter.cloned().collect();
+            let mut depth = 0;
+            let else_at = rest.iter().position(|lexer::TokenAndPos(_, token)| match token {
+                token::Token::If => {
+                    depth += 1;
+                    false
+                }
+                token::Token::Else if depth > 0 => {
+                    depth -= 1;
+                    false
+                }
+                token::Token::Else => true,
+                _ => false,
+            });
+
+            let branch = match (truth, else_at) {
+                (true, Some(index)) => &rest[..index],
+                (true, None) => &rest[..],
+                (false, Some(index)) => &rest[index + 1..],
+                // A false condition without ELSE skips the rest of the line
+                (false, None) => return Ok(String::new()),
+            };
+            let mut branch_iter = branch.iter().peekable();
+
+            match branch_iter.next() {
+                Some(&lexer::TokenAndPos(_, token::Token::Number(number))) => {
+                    *line_has_goto = true;
+                    let n = match to_line_number(number) {
+                        Ok(n) => n,
+                        Err(e) => err!(line_number, pos, e.kind, "Invalid IF target: {}", e),
+                    };
+                    match line_map.get(&n) {
+                        Some(index) => *line_index = *index,
+                        _ => err!(line_number, pos, ErrorKind::BadGoto, "Invalid target line for IF"),
                     }
-                
-                _ => err!(line_number, pos, "Invalid syntax for IF"),
+                }
+
+                // A lone name can't be a statement, so it's a label
+                Some(&lexer::TokenAndPos(_, token::Token::Variable(ref label))) if branch.len() == 1 => {
+                    *line_has_goto = true;
+                    match labels.get(label) {
+                        Some(index) => *line_index = *index,
+                        None => err!(line_number, pos, ErrorKind::BadGoto, "IF jumps to undefined label {}", label),
+                    }
+                }
+
+                Some(lexer::TokenAndPos(spos, stoken)) => {
+                    return evaluate_com(
+                        context,
+                        input,
+                        output,
+                        lineno_to_code,
+                        loop_partners,
+                        line_map,
+                        labels,
+                        line_numbers,
+                        line_index,
+                        line_has_goto,
+                        should_halt,
+                        is_isub,
+                        branch_iter,
+                        line_number,
+                        *spos,
+                        stoken,
+                    );
+                }
+
+                None => err!(line_number, pos, ErrorKind::Syntax, "THEN and ELSE must be followed by a line number, a label or a statement"),
             }
         }
 
@@ -268,145 +1586,181 @@ fn evaluate_com(
             match (
                 token_iter.next(),
                 token_iter.next(),
-                parse_and_eval_expression(&mut token_iter, &context),
+                parse_and_eval_expression(&mut token_iter, context, input),
             ) {
                 (
                     Some(&lexer::TokenAndPos(_, token::Token::Variable(ref variable))),
                     Some(&lexer::TokenAndPos(_, token::Token::Equals)),
-                    Ok(value::Value::Number(ref start)),
+                    Ok(start @ (value::Value::Int(_) | value::Value::Number(_))),
                 ) => {
-                    context
-                        .variables
-                        .insert(variable.to_string(), value::Value::Number(*start));
+                    // The counter keeps the start's type, so FOR I = 1 TO 10 counts in Ints
+                    let start_number = match start.clone().into_float() {
+                        value::Value::Number(number) => number,
+                        _ => unreachable!(),
+                    };
+                    if let Err(e) = context.set_variable(variable, start) {
+                        err!(line_number, pos, e.kind, "{}", e);
+                    }
 
                     match (
                         token_iter.next(),
-                        parse_and_eval_expression(&mut token_iter, &context),
+                        parse_and_eval_expression(&mut token_iter, context, input).map(value::Value::into_float),
                     ) {
                         (
-                            Some(&lexer::TokenAndPos(epos, token::Token::To)),
+                            Some(&lexer::TokenAndPos(_, token::Token::To)),
                             Ok(value::Value::Number(ref end)),
                         ) => {
-                            let stes = match token_iter.next() {
+                            // Without STEP the loop counts up by 1, so FOR I = 5 TO 1 never runs
+                            let step = match token_iter.next() {
                                 Some(&lexer::TokenAndPos(_, token::Token::Step)) => {
-                                    match parse_and_eval_expression(&mut token_iter, &context) {
-                                        Ok(value::Value::Number(ref _step)) => true,
-                                        _ => err!(line_number, pos, "Cannot parse FOR step"),
+                                    match parse_and_eval_expression(&mut token_iter, context, input).map(value::Value::into_float) {
+                                        Ok(value::Value::Number(step)) => step,
+                                        _ => err!(line_number, pos, ErrorKind::Syntax, "Cannot parse FOR step"),
                                     }
                                 },
-                                _ => false,
+                                _ => 1.0,
                             };
 
+                            // A start already past the end runs the body zero times, so
+                            // carry on after the matching NEXT without opening the loop
+                            if if step < 0.0 { start_number < *end } else { start_number > *end } {
+                                context.floops.remove(variable);
+                                context.for_order.retain(|open| open != variable);
+
+                                match matching_next(lineno_to_code, line_numbers, *line_index, variable) {
+                                    Some(index) => *line_index = index,
+                                    None => err!(line_number, pos, ErrorKind::Syntax, "FOR {} without matching NEXT", variable),
+                                }
+                                return Ok(String::new());
+                            }
+
                             context
                                 .floops
                                 .insert(variable.to_string(), ForLoop {
                                     line_no: **line_number,
-                                    pos: epos,
-                                    slide: *start < *end,
-                                    stes: stes});
+                                    end: *end,
+                                    step});
+                            context.for_order.retain(|open| open != variable);
+                            context.for_order.push(variable.to_string());
                         },
 
-                        _ => err!(line_number, pos, "Cannot parse secondary FOR expression"),
+                        _ => err!(line_number, pos, ErrorKind::Syntax, "Cannot parse secondary FOR expression"),
                     }
                 }
 
-                _ => err!(line_number, pos, "Cannot parse FOR initialisation expression"),
+                _ => err!(line_number, pos, ErrorKind::Syntax, "Cannot parse FOR initialisation expression"),
             }
         }
 
         token::Token::Next => {
-            match token_iter.next() {
-                Some(&lexer::TokenAndPos(_, token::Token::Variable(ref variable))) => {
-                    let floop = match context
-                        .floops
-                        .get(variable) {
-                            Some(floop) => floop,
-                            None => err!(line_number, pos, "Cannot get FOR signature from hashmap"),
-                    };
-                    
-                    let mut ftok_iter = &mut lineno_to_code[&floop.line_no]
-                        .iter()
-                        .peekable();
+            // A bare NEXT closes the most recently opened loop
+            let variable = match token_iter.next() {
+                Some(&lexer::TokenAndPos(_, token::Token::Variable(ref variable))) => variable.clone(),
+                None | Some(&lexer::TokenAndPos(_, token::Token::Rem)) => match context.for_order.last() {
+                    Some(variable) => variable.clone(),
+                    None => err!(line_number, pos, ErrorKind::NextWithoutFor, "NEXT without matching FOR"),
+                },
+                _ => err!(line_number, pos, ErrorKind::Syntax, "Invalid syntax for NEXT"),
+            };
+            let variable = &variable;
+
+            // Loops are looked up by variable rather than by position, so a body
+            // entered through GOTO still finds its FOR as long as it was executed.
+            // A loop that already finished is gone, so a stray NEXT after it
+            // fails the same way as one that never had a FOR
+            let floop = match context
+                .floops
+                .get(variable) {
+                    Some(floop) => floop,
+                    None => err!(line_number, pos, ErrorKind::NextWithoutFor, "NEXT {} without matching FOR", variable),
+            };
 
-                    while ftok_iter.peek().unwrap().0 != floop.pos {
-                        ftok_iter.next();
-                    }
+            let (end, step, for_line) = (floop.end, floop.step, floop.line_no);
 
-                    ftok_iter.next();
-                    let end = match parse_and_eval_expression(&mut ftok_iter, &context) {
-                        Ok(value::Value::Number(value)) => value,
-                        _ => err!(line_number, pos, "Cannot parse end for FOR"),
-                    };
-                
-                    let step = if floop.stes {
-                        match parse_and_eval_expression(&mut token_iter, &context) {
-                            Ok(value::Value::Number(value)) => value,
-                            _ => err!(line_number, pos, "Cannot parse step for FOR"),
-                        }
-                    }
-                    else {
-                        if floop.slide { 1 as f64 } else { -1 as f64 }
-                    };
+            // An Int counter only turns into a Number for a fractional step
+            let next = match context.lookup(variable) {
+                Some(value::Value::Int(int)) if step.fract() == 0.0 => match int.checked_add(step as i64) {
+                    Some(next) => value::Value::Int(next),
+                    None => value::Value::Number(int as f64 + step),
+                },
+                Some(value::Value::Int(int)) => value::Value::Number(int as f64 + step),
+                Some(value::Value::Number(number)) => value::Value::Number(number + step),
+                Some(_) => err!(line_number, pos, ErrorKind::Syntax, "Cannot parse variable for jump"),
+                None => err!(line_number, pos, ErrorKind::UndefinedVariable, "Invalid variable expression {}", variable),
+            };
+            let next_number = match next.clone().into_float() {
+                value::Value::Number(number) => number,
+                _ => unreachable!(),
+            };
 
-                    let next = *match get_variable!(context, variable, line_number, pos) {
-                        value::Value::Number(value) => value,
-                        _ => err!(line_number, pos, "Cannot parse variable for jump"),
-                    } + step;
-                    
-                    if if floop.slide { next < end } else { next > end } {
-                        context
-                            .variables
-                            .insert(variable.to_string(), value::Value::Number(next));
-                        
-                        match line_map.get(&floop.line_no) {
-                            Some(index) => *line_index = *index,
-                            None => err!(line_number, pos, "Invalid target line for NEXT"),
-                        }
-                    }
-                    else {
-                        context
-                            .floops
-                            .remove(variable);
-                    }
+            // The endpoint itself still runs the body
+            if if step < 0.0 { next_number >= end } else { next_number <= end } {
+                if let Err(e) = context.set_variable(variable, next) {
+                    err!(line_number, pos, e.kind, "{}", e);
                 }
 
-                None => err!(line_number, pos, "No jumping iterator passed"),
-
-                _ => err!(line_number, pos, "Invalid syntax for NEXT"),
+                match line_map.get(&for_line) {
+                    Some(index) => *line_index = *index,
+                    None => err!(line_number, pos, ErrorKind::BadGoto, "Invalid target line for NEXT"),
+                }
+            }
+            else {
+                context
+                    .floops
+                    .remove(variable);
+                context.for_order.retain(|open| open != variable);
             }
         }
 
         token::Token::While => {
-            match parse_and_eval_expression(&mut token_iter, &context) {
-                Ok(value::Value::Bool(_)) => context
+            // The condition is compiled once here and re-evaluated by WEND
+            let condition = match parse_expression(&mut token_iter) {
+                Ok(condition) => condition,
+                Err(_) => err!(line_number, pos, ErrorKind::Syntax, "Invalid boolean expression"),
+            };
+
+            // Coming back to a WHILE, e.g. an inner loop on the next pass of an
+            // outer one, starts it afresh instead of stacking a second copy
+            if let Some(open) = context.wloops.iter().position(|wloop| wloop.line_no == **line_number) {
+                context.wloops.truncate(open);
+            }
+
+            match eval_expression(&condition, context, input) {
+                Ok(value::Value::Bool(true)) => context
                             .wloops
-                            .push(WhileLoop { line_no: **line_number, pos: pos }),
+                            .push(WhileLoop { line_no: **line_number, condition }),
+
+                // A false condition skips the body entirely
+                Ok(value::Value::Bool(false)) => match loop_partners.get(line_index) {
+                    Some(index) => *line_index = *index,
+                    None => err!(line_number, pos, ErrorKind::Syntax, "WHILE without WEND"),
+                },
 
-                Err(_) => err!(line_number, pos, "Invalid boolean expression"),
+                Err(e) => err!(line_number, pos, e.kind, "Error in WHILE condition: {}", e),
 
-                _ => err!(line_number, pos, "Invalid expression type (expected boolean)"),
+                _ => err!(line_number, pos, ErrorKind::TypeMismatch, "Invalid expression type (expected boolean)"),
             }
         }
 
         token::Token::Wend => {
-            let wloops = &context.wloops;
-            let wloop = match wloops.last() {
-                Some(wl) => wl,
-                None => err!(line_number, pos, "Cannot find last WHILE loop"),
+            // Pair up with the WHILE this WEND closes rather than whichever ran last
+            let while_line = match loop_partners.get(line_index) {
+                Some(index) => **line_numbers[*index],
+                None => err!(line_number, pos, ErrorKind::Syntax, "WEND without WHILE"),
             };
+            let open = match context.wloops.iter().rposition(|wloop| wloop.line_no == while_line) {
+                Some(open) => open,
+                None => err!(line_number, pos, ErrorKind::Syntax, "WEND reached without an active WHILE at line {}", while_line.0),
+            };
+            context.wloops.truncate(open + 1);
+            let wloop = &context.wloops[open];
 
-            let mut wtok_iter = &mut lineno_to_code[&wloop.line_no]
-                .iter()
-                .peekable();
-
-            wtok_iter.next();
-
-            match parse_and_eval_expression(&mut wtok_iter, &context) {
+            match eval_expression(&wloop.condition, context, input) {
                 Ok(value::Value::Bool(truth)) => {
                     if truth {
                         match line_map.get(&wloop.line_no) {
                             Some(index) => *line_index = *index,
-                            None => err!(line_number, pos, "Invalid target line for WHILE"),
+                            None => err!(line_number, pos, ErrorKind::BadGoto, "Invalid target line for WHILE"),
                         }
                     }
                     else {
@@ -416,288 +1770,2315 @@ fn evaluate_com(
                     }
                 }
 
-                Err(_) => err!(line_number, pos, "Invalid boolean expression"),
+                Err(e) => err!(line_number, pos, e.kind, "Error in WHILE condition: {}", e),
 
-                _ => err!(line_number, pos, "Invalid expression"),
+                _ => err!(line_number, pos, ErrorKind::Syntax, "Invalid expression"),
             }
         }
 
         token::Token::Gosub => {
-            let ident = match match token_iter.next() {
-                Some(x) => x,
-                None => err!(line_number, pos, "Cannot get subroutine identifier"),
-            }.1.clone() {
-                token::Token::Srout(s) => s,
-                _ => err!(line_number, pos, "Expected subroutine identifier"),
+            let ident = match token_iter.peek() {
+                Some(&&lexer::TokenAndPos(_, token::Token::Srout(ref s))) => s.clone(),
+                Some(_) => {
+                    // Line number target, either literal or computed: GOSUB 100, GOSUB (BASE + N)
+                    let n = match parse_and_eval_expression(&mut token_iter, context, input).map(value::Value::into_float) {
+                        Ok(value::Value::Number(number)) => match to_line_number(number) {
+                            Ok(n) => n,
+                            Err(e) => err!(line_number, pos, e.kind, "Invalid GOSUB target: {}", e),
+                        },
+                        Ok(_) => err!(line_number, pos, ErrorKind::BadGoto, "GOSUB target must be a whole line number"),
+                        Err(e) => err!(line_number, pos, e.kind, "Error in GOSUB expression: {}", e),
+                    };
+
+                    match line_map.get(&n) {
+                        Some(index) => {
+                            context.enter_gosub(*line_index);
+                            *line_has_goto = true;
+                            *line_index = *index;
+                        }
+                        None => err!(line_number, pos, ErrorKind::BadGoto, "GOSUB to missing line {}", n.0),
+                    }
+
+                    return Ok(String::new());
+                }
+                None => err!(line_number, pos, ErrorKind::Syntax, "Cannot get subroutine identifier"),
             };
 
             if context.subs.contains_key(&ident) {
-                context.subsr.push(Subsr {
-                    call_no: **line_number,
-                    sident: ident.clone(),
-                });
+                context.enter_gosub(*line_index);
 
                 match line_map.get(&match context.subs.get(&ident) {
                     Some(sub) => sub.line_no,
-                    None => err!(line_number, pos, "Cannot get jumping reference"),
+                    None => err!(line_number, pos, ErrorKind::Syntax, "Cannot get jumping reference"),
                 }) {
                     Some(index) => *line_index = *index,
-                    None => err!(line_number, pos, "Cannot jump to subroutine"),
+                    None => err!(line_number, pos, ErrorKind::Syntax, "Cannot jump to subroutine"),
                 }
+            } else if let Some(index) = labels.get(&ident) {
+                // A SUB takes precedence over a label of the same name
+                context.enter_gosub(*line_index);
+                *line_has_goto = true;
+                *line_index = *index;
             } else {
-                err!(line_number, pos, "Subroutine definition uninitialised");
+                err!(line_number, pos, ErrorKind::Syntax, "Subroutine definition uninitialised");
             }
         }
 
-        token::Token::Sub => {
-            if *is_isub != None {
-                err!(line_number, pos, "Subroutines cannot be nested");
-            }
-
-            let ident = match match token_iter.next() {
-                Some(x) => x,
-                None => err!(line_number, pos, "Cannot get subroutine identifier"),
-            }.1.clone() {
-                token::Token::Srout(s) => s,
-                _ => err!(line_number, pos, "Expected subroutine identifier"),
+        token::Token::Dumptok => {
+            // Prints the lexed tokens of a line, for debugging the lexer
+            let n = match parse_and_eval_expression(&mut token_iter, context, input).map(value::Value::into_float) {
+                Ok(value::Value::Number(number)) => match to_line_number(number) {
+                    Ok(n) => n,
+                    Err(e) => err!(line_number, pos, e.kind, "Invalid DUMPTOK line: {}", e),
+                },
+                Err(e) => err!(line_number, pos, e.kind, "Error in DUMPTOK expression: {}", e),
+                _ => err!(line_number, pos, ErrorKind::Syntax, "DUMPTOK must be followed by a line number"),
             };
 
-            if context.subs.contains_key(&ident) { // Skip definition if it already exists
-                match line_map.get(&match context.subs.get(&ident) {
-                    Some(x) => x,
-                    None => err!(line_number, pos, ""),
-                }.ret_no) {
-                    Some(index) => *line_index = *index,
-                    None => err!(line_number, pos, "Cannot skip to subroutine end"),
+            match lineno_to_code.get(&n) {
+                Some(tokens) => {
+                    let dump: Vec<String> = tokens
+                        .iter()
+                        .map(|lexer::TokenAndPos(_, token)| format!("{:?}", token))
+                        .collect();
+                    let text = format!("{} {}\n", n.0, dump.join(" "));
+                    write_output!(line_number, pos, print_text(context, output, &text));
                 }
-            } else { // Wait for return
-                *is_isub = Some((ident, **line_number));
+                None => err!(line_number, pos, ErrorKind::BadGoto, "DUMPTOK of missing line {}", n.0),
             }
         }
 
-        token::Token::Return => {
-            match &*is_isub {
-                Some(x) => {
-                    match context.subs.insert(x.0.clone(), Sub {
-                        line_no: x.1,
-                        ret_no: **line_numbers[*line_index + 1],
-                    }) {
-                        Some(_) => {},
-                        None => {},
-                    };
-
-                    *is_isub = None;
-                },
-
-                None => {
-                    let last = match context.subsr.last() {
-                        Some(sr) => sr,
-                        None => err!(line_number, pos, "Cannot find last subroutine call"),
-                    };
-        
-                    match line_map.get(&last.call_no) {
-                        Some(index) => *line_index = *index + 1,
-                        None => err!(line_number, pos, "Cannot jump back to subroutine call"),
-                    }        
+        token::Token::Screen => {
+            // Expected Next:
+            // [EXPRESSION Comma EXPRESSION]
+            if token_iter.peek().is_none() {
+                // A bare SCREEN shows what has been drawn so far
+                match context.screen {
+                    Some(ref screen) => {
+                        let text = screen.to_text();
+                        write_output!(line_number, pos, print_text(context, output, &text));
+                    }
+                    None => err!(line_number, pos, ErrorKind::IllegalFunctionCall, "SCREEN has not been set up"),
+                }
+            } else {
+                let width = match parse_and_eval_expression(&mut token_iter, context, input) {
+                    Ok(value) => to_index(&value),
+                    Err(e) => Err(e),
+                };
+                let height = match (token_iter.next(), parse_and_eval_expression(&mut token_iter, context, input)) {
+                    (Some(&lexer::TokenAndPos(_, token::Token::Comma)), Ok(value)) => to_index(&value),
+                    _ => Err(Error::new(ErrorKind::Syntax, "expected a width and a height".to_string())),
+                };
+
+                let screen = match (width, height) {
+                    (Ok(width), Ok(height)) => screen::Screen::new(width, height),
+                    (Err(e), _) | (_, Err(e)) => Err(e),
+                };
+                match screen {
+                    Ok(screen) => context.screen = Some(screen),
+                    Err(e) => err!(line_number, pos, e.kind, "Invalid SCREEN size: {}", e),
                 }
             }
         }
 
-        _ => err!(line_number, pos, "Invalid syntax"),
-    }
-    
-    return Ok(String::new());
-}
-
-fn parse_expression(
-    token_iter: &mut Peekable<Iter<'_, lexer::TokenAndPos>>,
-) -> Result<VecDeque<token::Token>, String> {
-    let mut output_queue: VecDeque<token::Token> = VecDeque::new();
-    let mut operator_stack: Vec<token::Token> = Vec::new();
+        token::Token::Pset => {
+            // Expected Next:
+            // LParen EXPRESSION Comma EXPRESSION RParen
+            let point = match parse_and_eval_point(&mut token_iter, context, input) {
+                Ok(point) => point,
+                Err(e) => err!(line_number, pos, e.kind, "Invalid PSET point: {}", e),
+            };
 
-    loop {
-        match token_iter.peek() {
-            Some(&&lexer::TokenAndPos(_, token::Token::Then)) |
-            Some(&&lexer::TokenAndPos(_, token::Token::To)) |
-            Some(&&lexer::TokenAndPos(_, token::Token::Semicolon)) |
-            Some(&&lexer::TokenAndPos(_, token::Token::Step)) |
-            None => break,
-            _ => {}
+            let screen = match context.screen {
+                Some(ref mut screen) => screen,
+                None => err!(line_number, pos, ErrorKind::IllegalFunctionCall, "PSET before SCREEN"),
+            };
+            if let Err(e) = screen.set(point.0, point.1) {
+                err!(line_number, pos, e.kind, "{}", e);
+            }
         }
 
-        //println!("iter: {:?}", token_iter);
+        token::Token::Line => {
+            // Expected Next:
+            // LParen EXPRESSION Comma EXPRESSION RParen Minus LParen EXPRESSION Comma EXPRESSION RParen
+            let from = match parse_and_eval_point(&mut token_iter, context, input) {
+                Ok(point) => point,
+                Err(e) => err!(line_number, pos, e.kind, "Invalid LINE start: {}", e),
+            };
+            match token_iter.next() {
+                Some(&lexer::TokenAndPos(_, token::Token::Minus)) => {}
+                _ => err!(line_number, pos, ErrorKind::Syntax, "LINE points must be separated by -"),
+            }
+            let to = match parse_and_eval_point(&mut token_iter, context, input) {
+                Ok(point) => point,
+                Err(e) => err!(line_number, pos, e.kind, "Invalid LINE end: {}", e),
+            };
 
-        match token_iter.next() {
-            Some(&lexer::TokenAndPos(_, ref value_token)) if value_token.is_value() => {
-                output_queue.push_back(value_token.clone())
+            let screen = match context.screen {
+                Some(ref mut screen) => screen,
+                None => err!(line_number, pos, ErrorKind::IllegalFunctionCall, "LINE before SCREEN"),
+            };
+            if let Err(e) = screen.line(from, to) {
+                err!(line_number, pos, e.kind, "{}", e);
             }
-            Some(&lexer::TokenAndPos(_, ref op_token)) if op_token.is_operator() => {
-                if !operator_stack.is_empty() {
-                    let top_op = operator_stack.last().unwrap().clone();
-                    if top_op.is_operator() {
-                        let associativity = op_token.operator_associavity().unwrap();
-
-                        if (associativity == token::Associativity::Left
-                            && op_token.operator_precedence() <= top_op.operator_precedence())
-                            || (associativity == token::Associativity::Right
-                                && op_token.operator_precedence() < top_op.operator_precedence())
-                        {
-                            let top_op = operator_stack.pop().unwrap();
-                            output_queue.push_back(top_op.clone());
-                        }
-                    }
+        }
+
+        token::Token::Precision => {
+            // Expected Next:
+            // EXPRESSION, where 0 goes back to the default
+            context.precision = match parse_and_eval_expression(&mut token_iter, context, input).map(value::Value::into_float) {
+                Ok(value::Value::Number(0.0)) => None,
+                Ok(value::Value::Number(digits)) if digits.fract() == 0.0 && digits > 0.0 && digits <= 17.0 => {
+                    Some(digits as usize)
                 }
+                Err(e) => err!(line_number, pos, e.kind, "Error in PRECISION expression: {}", e),
+                _ => err!(line_number, pos, ErrorKind::Syntax, "PRECISION must be followed by a number of digits from 0 to 17"),
+            };
+        }
 
-                operator_stack.push(op_token.clone());
+        token::Token::Shell => {
+            // Expected Next:
+            // EXPRESSION
+            if !context.allow_shell {
+                err!(line_number, pos, ErrorKind::IllegalFunctionCall, "SHELL not permitted");
             }
-            Some(&lexer::TokenAndPos(_, token::Token::LParen)) => {
-                operator_stack.push(token::Token::LParen);
+
+            let command = match parse_and_eval_expression(&mut token_iter, context, input) {
+                Ok(value::Value::String(command)) => command,
+                Err(e) => err!(line_number, pos, e.kind, "Error in SHELL expression: {}", e),
+                _ => err!(line_number, pos, ErrorKind::Syntax, "SHELL must be followed by a command string"),
+            };
+
+            let result = if cfg!(windows) {
+                process::Command::new("cmd").args(["/C", command.as_str()]).output()
+            } else {
+                process::Command::new("sh").args(["-c", command.as_str()]).output()
+            };
+
+            match result {
+                Ok(result) => {
+                    // Both of the command's streams join the program's output, stdout first
+                    let text = String::from_utf8_lossy(&result.stdout);
+                    write_output!(line_number, pos, print_text(context, output, &text));
+                    let text = String::from_utf8_lossy(&result.stderr);
+                    write_output!(line_number, pos, print_text(context, output, &text));
+                    // Killed by a signal counts as failure
+                    context.exit_code = result.status.code().unwrap_or(-1);
+                }
+                Err(e) => err!(line_number, pos, ErrorKind::Io, "SHELL could not run \"{}\": {}", command, e),
             }
-            Some(&lexer::TokenAndPos(_, token::Token::RParen)) => loop {
-                match operator_stack.pop() {
-                    Some(token::Token::LParen) => break,
-                    Some(ref next_token) => output_queue.push_back(next_token.clone()),
-                    None => return Err("Mismatched parenthesis in expression".to_string()),
+        }
+
+        token::Token::Eval => {
+            // Only an expression is accepted, never a statement, so EVAL can't nest
+            let source = match parse_and_eval_expression(&mut token_iter, context, input) {
+                Ok(value::Value::String(source)) => source,
+                Err(e) => err!(line_number, pos, e.kind, "Error in EVAL expression: {}", e),
+                _ => err!(line_number, pos, ErrorKind::Syntax, "EVAL must be followed by a string"),
+            };
+
+            let tokens = match lexer::tokenize_expression(&source) {
+                Ok(tokens) => tokens,
+                Err(e) => err!(line_number, pos, ErrorKind::Syntax, "EVAL of \"{}\" failed: {}", source, e),
+            };
+
+            let mut eval_iter = tokens.iter().peekable();
+            let value = match parse_expression(&mut eval_iter) {
+                Ok(_) if eval_iter.peek().is_some() => {
+                    err!(line_number, pos, ErrorKind::Syntax, "EVAL of \"{}\" failed: unexpected text after expression", source)
                 }
-            },
-            _ => {
-                unreachable!();
-            },
+                Ok(output_queue) => match eval_expression(&output_queue, context, input) {
+                    Ok(value) => value,
+                    Err(e) => err!(line_number, pos, e.kind, "EVAL of \"{}\" failed: {}", source, e),
+                },
+                Err(e) => err!(line_number, pos, e.kind, "EVAL of \"{}\" failed: {}", source, e),
+            };
+
+            match value {
+                value::Value::Array { .. } => err!(line_number, pos, ErrorKind::TypeMismatch, "Cannot EVAL to a whole array"),
+                value => {
+                    let text = format!("{}\n", format_value(&value, context));
+                    write_output!(line_number, pos, print_text(context, output, &text));
+                }
+            }
         }
-    }
 
-    while !operator_stack.is_empty() {
-        match operator_stack.pop().unwrap() {
-            token::Token::LParen | token::Token::RParen => {
-                return Err("Mismatched parenthesis in expression.".to_string())
+        token::Token::On if matches!(token_iter.peek(), Some(&&lexer::TokenAndPos(_, token::Token::Error))) => {
+            // Expected Next:
+            // Error Goto Number
+            token_iter.next();
+            match (token_iter.next(), token_iter.next()) {
+                (
+                    Some(&lexer::TokenAndPos(_, token::Token::Goto)),
+                    Some(&lexer::TokenAndPos(tpos, token::Token::Number(number))),
+                ) => {
+                    // ON ERROR GOTO 0 turns trapping off again
+                    context.error_handler = match to_line_number(number) {
+                        Ok(lexer::LineNumber(0)) => None,
+                        Ok(n) if line_map.contains_key(&n) => Some(n),
+                        // Caught now, a handler that isn't there would leave errors untrapped
+                        Ok(n) => err!(line_number, tpos, ErrorKind::BadGoto, "Invalid target line for ON ERROR, there is no line {}", n.0),
+                        Err(e) => err!(line_number, tpos, e.kind, "Invalid ON ERROR target: {}", e),
+                    };
+                }
+                _ => err!(line_number, pos, ErrorKind::Syntax, "Invalid syntax for ON ERROR, expected GOTO and a line number"),
             }
-            op_token => output_queue.push_back(op_token.clone()),
         }
-    }
 
-    Ok(output_queue)
-}
+        token::Token::Resume => {
+            // Expected Next:
+            // [Next | Number]
+            let error_index = match context.resume_index.take() {
+                Some(index) => index,
+                None => err!(line_number, pos, ErrorKind::ResumeWithoutError, "RESUME without error"),
+            };
 
-fn parse_and_eval_expression<'a>(
-    token_iter: &mut Peekable<Iter<'a, lexer::TokenAndPos>>,
-    context: &Context,
-) -> Result<value::Value, String> {
-    match parse_expression(token_iter) {
-        Ok(mut output_queue) => {
-            let mut stack: Vec<value::Value> = Vec::new();
-
-            while !output_queue.is_empty() {
-                match output_queue.pop_front() {
-                    Some(token::Token::Number(ref number)) => {
-                        stack.push(value::Value::Number(*number))
-                    }
-                    Some(token::Token::BString(ref bstring)) => {
-                        let mut checked = String::new();
-                        let mut is_ep = false;
-
-                        for c in bstring.chars().peekable() {
-                            if c == '\\' {
-                                is_ep = true;                                
-                            } else {
-                                checked.push(if is_ep { match c {
-                                    'e' => '\x1b',
-                                    'n' => '\n',
-                                    '\\' => '\\',
-                                    _ => c,
-                                }} else {
-                                    c
-                                });
-
-                                is_ep = false;
-                            };
-                        }
+            *line_has_goto = true;
+            *line_index = match token_iter.next() {
+                // Retries the line that failed
+                None => error_index,
+                Some(&lexer::TokenAndPos(_, token::Token::Next)) => error_index + 1,
+                Some(&lexer::TokenAndPos(tpos, token::Token::Number(number))) => {
+                    match to_line_number(number).ok().and_then(|n| line_map.get(&n)) {
+                        Some(index) => *index,
+                        None => err!(line_number, tpos, ErrorKind::BadGoto, "RESUME to missing line {}", number),
+                    }
+                }
+                Some(&lexer::TokenAndPos(tpos, _)) => err!(line_number, tpos, ErrorKind::Syntax, "Invalid syntax for RESUME"),
+            };
+        }
 
-                        stack.push(value::Value::String(checked));
+        token::Token::On => {
+            // Expected Next:
+            // EXPRESSION (Goto | Gosub) (Number | Variable) [Comma (Number | Variable)]...
+            let selector = match parse_and_eval_expression(&mut token_iter, context, input).map(value::Value::into_float) {
+                Ok(value::Value::Number(number)) => number,
+                Ok(_) => err!(line_number, pos, ErrorKind::TypeMismatch, "ON must be followed by a numeric expression"),
+                Err(e) => err!(line_number, pos, e.kind, "Error in ON expression: {}", e),
+            };
+
+            let is_gosub = match token_iter.next() {
+                Some(&lexer::TokenAndPos(_, token::Token::Goto)) => false,
+                Some(&lexer::TokenAndPos(_, token::Token::Gosub)) => true,
+                _ => err!(line_number, pos, ErrorKind::Syntax, "Invalid syntax for ON, expected GOTO or GOSUB"),
+            };
+            let keyword = if is_gosub { "GOSUB" } else { "GOTO" };
+
+            // Targets that don't exist are only an error once they are picked
+            let mut targets = Vec::new();
+            loop {
+                match token_iter.next() {
+                    Some(&lexer::TokenAndPos(tpos, token::Token::Number(number))) => {
+                        match to_line_number(number) {
+                            Ok(n) => targets.push(
+                                line_map
+                                    .get(&n)
+                                    .copied()
+                                    .ok_or_else(|| Error::new(ErrorKind::BadGoto, format!("missing line {}", n.0))),
+                            ),
+                            Err(e) => err!(line_number, tpos, e.kind, "Invalid ON target: {}", e),
+                        }
+                    }
+                    // GOSUB puts Srout rather than Variable after itself
+                    Some(&lexer::TokenAndPos(_, token::Token::Variable(ref label)))
+                    | Some(&lexer::TokenAndPos(_, token::Token::Srout(ref label))) => {
+                        targets.push(
+                            labels
+                                .get(label)
+                                .copied()
+                                .ok_or_else(|| Error::new(ErrorKind::BadGoto, format!("undefined label {}", label))),
+                        )
                     }
-                    Some(token::Token::Variable(ref name)) => match context.variables.get(name) {
-                        Some(value) => stack.push(value.clone()),
-                        None => {
-                            return Err(format!(
-                                "Invalid variable reference {} in expression",
-                                name
-                            ))
+                    _ => err!(line_number, pos, ErrorKind::Syntax, "ON {} must be followed by a list of line numbers or labels", keyword),
+                }
+
+                match token_iter.next() {
+                    Some(&lexer::TokenAndPos(_, token::Token::Comma)) => continue,
+                    None => break,
+                    Some(&lexer::TokenAndPos(tpos, _)) => err!(line_number, tpos, ErrorKind::Syntax, "Expected , between ON targets"),
+                }
+            }
+
+            // 1-based; anything out of range falls through to the next line
+            let choice = selector.trunc();
+            if choice >= 1.0 && choice <= targets.len() as f64 {
+                match targets[choice as usize - 1] {
+                    Ok(index) => {
+                        if is_gosub {
+                            context.enter_gosub(*line_index);
                         }
-                    },
-                    Some(ref unary_token) if unary_token.is_unary_operator() => {
-                        if !stack.is_empty() {
-                            let value = stack.pop().unwrap();
-                            let result = match *unary_token {
-                                token::Token::UMinus => -value,
-                                token::Token::Bang => !value,
-                                // Pattern guard prevents any other match
-                                _ => unreachable!(),
-                            };
-                            match result {
-                                Ok(value) => stack.push(value),
-                                Err(e) => return Err(e),
-                            }
-                        } else {
-                            return Err(format!("Operator {:?} requires an operand!", unary_token));
+                        *line_has_goto = true;
+                        *line_index = index;
+                    }
+                    Err(ref e) => err!(line_number, pos, e.kind, "Invalid target for ON {}: {}", keyword, e),
+                }
+            }
+        }
+
+        token::Token::Sub => {
+            if is_isub.is_some() {
+                err!(line_number, pos, ErrorKind::Syntax, "Subroutines cannot be nested");
+            }
+
+            let ident = match match token_iter.next() {
+                Some(x) => x,
+                None => err!(line_number, pos, ErrorKind::Syntax, "Cannot get subroutine identifier"),
+            }.1.clone() {
+                token::Token::Srout(s) => s,
+                _ => err!(line_number, pos, ErrorKind::Syntax, "Expected subroutine identifier"),
+            };
+
+            if context.subs.contains_key(&ident) { // Skip definition if it already exists
+                match line_map.get(&match context.subs.get(&ident) {
+                    Some(x) => x,
+                    None => err!(line_number, pos, ErrorKind::Syntax, ""),
+                }.ret_no) {
+                    // Land on the RETURN; the main loop then steps past it
+                    Some(index) => *line_index = *index,
+                    None => err!(line_number, pos, ErrorKind::Syntax, "Cannot skip to subroutine end"),
+                }
+            } else { // Wait for return
+                *is_isub = Some((ident, **line_number));
+            }
+        }
+
+        token::Token::Return => {
+            match &*is_isub {
+                Some(x) => {
+                    context.subs.insert(x.0.clone(), Sub {
+                        line_no: x.1,
+                        ret_no: **line_numbers[*line_index],
+                    });
+
+                    *is_isub = None;
+                },
+
+                None => {
+                    let call_index = match context.leave_gosub() {
+                        Some(index) => index,
+                        None => err!(line_number, pos, ErrorKind::ReturnWithoutGosub, "RETURN without GOSUB"),
+                    };
+
+                    // Resume on the line after the GOSUB
+                    *line_has_goto = true;
+                    *line_index = call_index + 1;
+                }
+            }
+        }
+
+        token::Token::Read => {
+            // Expected Next:
+            // Variable [LParen EXPRESSION RParen] [Comma ...]
+            loop {
+                let variable = match token_iter.next() {
+                    Some(&lexer::TokenAndPos(_, token::Token::Variable(ref variable))) => variable,
+                    _ => err!(line_number, pos, ErrorKind::Syntax, "READ must be followed by a variable"),
+                };
+
+                let subscripts = match token_iter.peek() {
+                    Some(&&lexer::TokenAndPos(_, token::Token::LParen)) => {
+                        match parse_and_eval_subscripts(&mut token_iter, context, input) {
+                            Ok(subscripts) => Some(subscripts),
+                            Err(e) => err!(line_number, pos, e.kind, "Error in READ subscript: {}", e),
                         }
                     }
-                    Some(ref comparison_token) if comparison_token.is_comparison_operator() => {
-                        if stack.len() >= 2 {
-                            let operand2 = &stack.pop().unwrap();
-                            let operand1 = &stack.pop().unwrap();
-
-                            let result = match *comparison_token {
-                                token::Token::Equals => operand1.eq(operand2),
-                                token::Token::NotEqual => operand1.neq(operand2),
-                                token::Token::LessThan => operand1.lt(operand2),
-                                token::Token::GreaterThan => operand1.gt(operand2),
-                                token::Token::LessThanEqual => operand1.lteq(operand2),
-                                token::Token::GreaterThanEqual => operand1.gteq(operand2),
-                                // Pattern guard prevents any other match
-                                _ => unreachable!(),
-                            };
-                            match result {
-                                Ok(value) => stack.push(value::Value::Bool(value)),
-                                Err(e) => return Err(e),
-                            }
-                        } else {
-                            return Err(format!(
-                                "Comparison operator {:?} requires two operands",
-                                comparison_token
-                            ));
+                    _ => None,
+                };
+
+                let (data_line, item) = match context.data.get(context.data_cursor) {
+                    Some((data_line, item)) => (*data_line, item.clone()),
+                    None => err!(line_number, pos, ErrorKind::OutOfData, "Out of DATA"),
+                };
+                context.data_cursor += 1;
+
+                // Like classic BASIC, a bad item is reported on its DATA line
+                let value = match coerce_data(variable, item) {
+                    Some(value) => value,
+                    None => return Err(EvalError::new(data_line, 0, ErrorKind::Syntax, "Syntax error in DATA".to_string())),
+                };
+
+                match subscripts {
+                    Some(subscripts) => {
+                        if let Err(e) = context.set_element(variable, &subscripts, value) {
+                            err!(line_number, pos, e.kind, "{}", e);
                         }
                     }
-                    Some(ref binary_op_token) if binary_op_token.is_binary_operator() => {
-                        if stack.len() >= 2 {
-                            let operand2 = stack.pop().unwrap();
-                            let operand1 = stack.pop().unwrap();
-
-                            let result = match *binary_op_token {
-                                token::Token::Plus => operand1 + operand2,
-                                token::Token::Minus => operand1 - operand2,
-                                token::Token::Multiply => operand1 * operand2,
-                                token::Token::Divide => operand1 / operand2,
-                                // Pattern guard prevents any other match
-                                _ => unreachable!(),
-                            };
-                            match result {
-                                Ok(value) => stack.push(value),
-                                Err(e) => return Err(e),
-                            }
+                    None => {
+                        if let Err(e) = context.set_variable(variable, value) {
+                            err!(line_number, pos, e.kind, "{}", e);
                         }
                     }
-                    None => unreachable!(),
-                    _ => unreachable!(),
+                }
+
+                match token_iter.next() {
+                    Some(&lexer::TokenAndPos(_, token::Token::Comma)) => continue,
+                    None => break,
+                    _ => err!(line_number, pos, ErrorKind::Syntax, "Invalid syntax for READ"),
+                }
+            }
+        }
+
+        token::Token::Restore => {
+            // Expected Next:
+            // [EXPRESSION]
+            context.data_cursor = match token_iter.peek() {
+                None => 0,
+                Some(_) => {
+                    let target = match parse_and_eval_expression(&mut token_iter, context, input).map(value::Value::into_float) {
+                        Ok(value::Value::Number(number)) => match to_line_number(number) {
+                            Ok(target) => target,
+                            Err(e) => err!(line_number, pos, e.kind, "{}", e),
+                        },
+                        Err(e) => err!(line_number, pos, e.kind, "Error in RESTORE expression: {}", e),
+                        _ => err!(line_number, pos, ErrorKind::Syntax, "RESTORE must be followed by a line number"),
+                    };
+
+                    context
+                        .data
+                        .iter()
+                        .position(|(line, _)| *line >= target)
+                        .unwrap_or(context.data.len())
+                }
+            };
+        }
+
+        _ => err!(line_number, pos, ErrorKind::Syntax, "Invalid syntax"),
+    }
+    
+    Ok(String::new())
+}
+
+// Text of a string literal with its escapes applied: \e, \n and \\
+fn unescape(bstring: &str) -> String {
+    let mut checked = String::new();
+    let mut is_ep = false;
+
+    for c in bstring.chars() {
+        if c == '\\' && !is_ep {
+            is_ep = true;
+        } else {
+            checked.push(if is_ep { match c {
+                'e' => '\x1b',
+                'n' => '\n',
+                _ => c,
+            }} else {
+                c
+            });
+
+            is_ep = false;
+        };
+    }
+
+    checked
+}
+
+// Items are numbers, quoted strings, or bare words which are read as strings.
+// Each keeps its type, so READ gives 10 as a number and "10" as a string
+fn collect_data(
+    lineno_to_code: &BTreeMap<&lexer::LineNumber, &Vec<lexer::TokenAndPos>>,
+) -> Result<Vec<(lexer::LineNumber, value::Value)>, EvalError> {
+    let mut data = Vec::new();
+
+    for (line_number, tokens) in lineno_to_code.iter() {
+        let mut token_iter = tokens.iter();
+        let pos = match token_iter.next() {
+            Some(&lexer::TokenAndPos(pos, token::Token::Data)) => pos,
+            _ => continue,
+        };
+
+        loop {
+            let value = match token_iter.next() {
+                Some(lexer::TokenAndPos(_, token::Token::Number(number))) => value::Value::from_number(*number),
+                Some(lexer::TokenAndPos(_, token::Token::UMinus)) => match token_iter.next() {
+                    Some(lexer::TokenAndPos(_, token::Token::Number(number))) => value::Value::from_number(-number),
+                    _ => err!(line_number, pos, ErrorKind::Syntax, "Syntax error in DATA"),
+                },
+                Some(lexer::TokenAndPos(_, token::Token::BString(string))) => value::Value::String(unescape(string)),
+                Some(lexer::TokenAndPos(_, token::Token::Variable(word))) => value::Value::String(word.clone()),
+                _ => err!(line_number, pos, ErrorKind::Syntax, "Syntax error in DATA"),
+            };
+            data.push((**line_number, value));
+
+            // A trailing comment isn't another item
+            match token_iter.next() {
+                Some(lexer::TokenAndPos(_, token::Token::Comma)) => continue,
+                Some(lexer::TokenAndPos(_, token::Token::Rem)) | None => break,
+                _ => err!(line_number, pos, ErrorKind::Syntax, "Syntax error in DATA"),
+            }
+        }
+    }
+
+    Ok(data)
+}
+
+// READ gives a DATA item the type of the variable it goes into. A takes numbers
+// and strings that read as one, A$ takes strings and the text of numbers
+fn coerce_data(name: &str, item: value::Value) -> Option<value::Value> {
+    match (name.ends_with('$'), item) {
+        (true, value::Value::String(string)) => Some(value::Value::String(string)),
+        (true, number) => Some(value::Value::String(number.to_string())),
+        (false, value::Value::String(string)) => f64::from_str(string.trim())
+            .ok()
+            .filter(|number| number.is_finite())
+            .map(value::Value::from_number),
+        (false, number) => Some(number),
+    }
+}
+
+// MID$(A, start [, length]) = B overwrites part of the string in A in place,
+// never changing its length; the iterator is just past MID$
+fn assign_mid(
+    token_iter: &mut Peekable<Iter<'_, lexer::TokenAndPos>>,
+    context: &mut Context,
+    input: &mut dyn BufRead,
+) -> Result<(), Error> {
+    let variable = match (token_iter.next(), token_iter.next()) {
+        (
+            Some(&lexer::TokenAndPos(_, token::Token::LParen)),
+            Some(&lexer::TokenAndPos(_, token::Token::Variable(ref variable))),
+        ) => variable,
+        _ => return Err(Error::new(
+            ErrorKind::Syntax,
+            "MID$ assignment must be followed by (variable, start [, length])".to_string(),
+        )),
+    };
+
+    let mut arguments = Vec::new();
+    loop {
+        match token_iter.next() {
+            Some(&lexer::TokenAndPos(_, token::Token::Comma)) => {
+                arguments.push(parse_and_eval_expression(token_iter, context, input)?.into_float())
+            }
+            Some(&lexer::TokenAndPos(_, token::Token::RParen)) => break,
+            _ => return Err(Error::new(ErrorKind::Syntax, "Expected , or ) in MID$ assignment".to_string())),
+        }
+    }
+
+    match token_iter.next() {
+        Some(&lexer::TokenAndPos(_, token::Token::Equals)) => {}
+        _ => return Err(Error::new(ErrorKind::Syntax, "Expected = after MID$(...)".to_string())),
+    }
+    let replacement = match parse_and_eval_expression(token_iter, context, input)? {
+        value::Value::String(replacement) => replacement,
+        _ => return Err(Error::new(ErrorKind::TypeMismatch, "MID$ can only be assigned a string".to_string())),
+    };
+
+    let original = match context.lookup(variable) {
+        Some(value::Value::String(original)) => original,
+        Some(_) => return Err(Error::new(
+            ErrorKind::TypeMismatch,
+            format!("MID$ assignment needs {} to hold a string", variable),
+        )),
+        None => return Err(Error::new(
+            ErrorKind::UndefinedVariable,
+            format!("Invalid variable reference {} in MID$ assignment", variable),
+        )),
+    };
+    let length = original.chars().count();
+
+    let (start, count) = match arguments.as_slice() {
+        [value::Value::Number(start)] => (*start, replacement.chars().count()),
+        [value::Value::Number(start), value::Value::Number(count)] => (*start, to_count(*count)),
+        _ => return Err(Error::new(ErrorKind::Syntax, "MID$ assignment expects a start and an optional length".to_string())),
+    };
+    if start.fract() != 0.0 || start < 1.0 || start as usize > length {
+        return Err(Error::new(
+            ErrorKind::OutOfRange,
+            format!("MID$ start {} out of range for a string of length {}", start, length),
+        ));
+    }
+
+    let start = start as usize - 1;
+    let mut chars: Vec<char> = original.chars().collect();
+    for (target, c) in chars[start..].iter_mut().zip(replacement.chars().take(count)) {
+        *target = c;
+    }
+
+    context.set_variable(variable, value::Value::String(chars.into_iter().collect()))
+}
+
+// Change in WHILE nesting depth over one line
+fn while_depth(tokens: &[lexer::TokenAndPos]) -> i32 {
+    tokens
+        .iter()
+        .map(|lexer::TokenAndPos(_, token)| match token {
+            token::Token::While => 1,
+            token::Token::Wend => -1,
+            _ => 0,
+        })
+        .sum()
+}
+
+// Index of the line holding the WEND for the WHILE on line `index`
+fn matching_wend(
+    lineno_to_code: &BTreeMap<&lexer::LineNumber, &Vec<lexer::TokenAndPos>>,
+    line_numbers: &[&&lexer::LineNumber],
+    index: usize,
+) -> Option<usize> {
+    let mut depth = 0;
+
+    for (offset, line_number) in line_numbers[index..].iter().enumerate() {
+        depth += while_depth(lineno_to_code[*line_number]);
+        if depth <= 0 {
+            return Some(index + offset);
+        }
+    }
+
+    None
+}
+
+// Index of the line holding the WHILE for the WEND on line `index`
+fn matching_while(
+    lineno_to_code: &BTreeMap<&lexer::LineNumber, &Vec<lexer::TokenAndPos>>,
+    line_numbers: &[&&lexer::LineNumber],
+    index: usize,
+) -> Option<usize> {
+    let mut depth = 0;
+
+    for offset in (0..=index).rev() {
+        depth += while_depth(lineno_to_code[line_numbers[offset]]);
+        if depth >= 0 && offset < index {
+            return Some(offset);
+        }
+    }
+
+    None
+}
+
+// Index of the line holding the NEXT for the FOR of `variable` on line `index`,
+// either NEXT with that variable or a bare NEXT, skipping over nested loops
+fn matching_next(
+    lineno_to_code: &BTreeMap<&lexer::LineNumber, &Vec<lexer::TokenAndPos>>,
+    line_numbers: &[&&lexer::LineNumber],
+    index: usize,
+    variable: &str,
+) -> Option<usize> {
+    let mut depth = 0;
+
+    for (offset, line_number) in line_numbers[index + 1..].iter().enumerate() {
+        let mut tokens = lineno_to_code[*line_number]
+            .iter()
+            .map(|lexer::TokenAndPos(_, token)| token)
+            .skip_while(|token| matches!(token, token::Token::Label(_)));
+
+        match (tokens.next(), tokens.next()) {
+            (Some(token::Token::For), _) => depth += 1,
+            (Some(token::Token::Next), named) => {
+                let ours = match named {
+                    Some(token::Token::Variable(name)) => name == variable,
+                    _ => true,
+                };
+                if depth == 0 && ours {
+                    return Some(index + 1 + offset);
+                }
+                if depth > 0 {
+                    depth -= 1;
                 }
             }
+            _ => {}
+        }
+    }
+
+    None
+}
+
+fn to_line_number(number: f64) -> Result<lexer::LineNumber, Error> {
+    // Rather than truncating GOTO 10.5 to line 10
+    if number.fract() != 0.0 {
+        return Err(Error::new(ErrorKind::BadGoto, format!("Line number must be a whole number, got {}", number)));
+    }
+    if number < 0.0 || number > u32::MAX as f64 {
+        return Err(Error::new(
+            ErrorKind::BadGoto,
+            format!("Line number out of range, {} is not between 0 and {}", number, u32::MAX),
+        ));
+    }
 
-            // If expression is well formed, there will only be the result on the stack
-            if stack.len() != 1 {
-                return Err("Cannot parse expression".to_string());
+    Ok(lexer::LineNumber(number as u32))
+}
+
+fn to_index(value: &value::Value) -> Result<usize, Error> {
+    match value {
+        value::Value::Int(int) => usize::try_from(*int)
+            .map_err(|_| Error::new(ErrorKind::OutOfRange, format!("{} is not a valid subscript", int))),
+        value::Value::Number(number) if number.fract() == 0.0 && *number >= 0.0 => Ok(*number as usize),
+        value::Value::Number(number) => Err(Error::new(
+            ErrorKind::OutOfRange,
+            format!("{} is not a valid subscript", number),
+        )),
+        _ => Err(Error::new(ErrorKind::TypeMismatch, "Subscripts must be numbers".to_string())),
+    }
+}
+
+// A parenthesised, comma separated list of subscripts: (I) or (I, J)
+fn parse_and_eval_subscripts(
+    token_iter: &mut Peekable<Iter<'_, lexer::TokenAndPos>>,
+    context: &Context,
+    input: &mut dyn BufRead,
+) -> Result<Vec<value::Value>, Error> {
+    match token_iter.next() {
+        Some(&lexer::TokenAndPos(_, token::Token::LParen)) => {}
+        _ => return Err(Error::new(ErrorKind::Syntax, "Expected ( before subscripts".to_string())),
+    }
+
+    let mut subscripts = Vec::new();
+    loop {
+        subscripts.push(parse_and_eval_expression(token_iter, context, input)?);
+
+        match token_iter.next() {
+            Some(&lexer::TokenAndPos(_, token::Token::Comma)) => continue,
+            Some(&lexer::TokenAndPos(_, token::Token::RParen)) => break,
+            _ => return Err(Error::new(ErrorKind::Syntax, "Expected , or ) after subscript".to_string())),
+        }
+    }
+
+    Ok(subscripts)
+}
+
+// A screen coordinate: (X, Y)
+fn parse_and_eval_point(
+    token_iter: &mut Peekable<Iter<'_, lexer::TokenAndPos>>,
+    context: &Context,
+    input: &mut dyn BufRead,
+) -> Result<(i64, i64), Error> {
+    let coordinates = parse_and_eval_subscripts(token_iter, context, input)?;
+
+    match coordinates.as_slice() {
+        [value::Value::Int(x), value::Value::Int(y)] => Ok((*x, *y)),
+        [x, y] => match (x.clone().into_float(), y.clone().into_float()) {
+            (value::Value::Number(x), value::Value::Number(y)) if x.fract() == 0.0 && y.fract() == 0.0 => {
+                Ok((x as i64, y as i64))
             }
-            
-            Ok(stack[0].clone())
+            _ => Err(Error::new(ErrorKind::IllegalFunctionCall, "a point is two whole numbers (X, Y)".to_string())),
+        },
+        _ => Err(Error::new(ErrorKind::IllegalFunctionCall, "a point is two whole numbers (X, Y)".to_string())),
+    }
+}
+
+// A braced, comma separated list of values: {1, 2, "three"}
+fn parse_and_eval_list(
+    token_iter: &mut Peekable<Iter<'_, lexer::TokenAndPos>>,
+    context: &Context,
+    input: &mut dyn BufRead,
+) -> Result<Vec<value::Value>, Error> {
+    match token_iter.next() {
+        Some(&lexer::TokenAndPos(_, token::Token::LBrace)) => {}
+        _ => return Err(Error::new(ErrorKind::Syntax, "Expected { before list".to_string())),
+    }
+
+    let mut values = Vec::new();
+    if let Some(&&lexer::TokenAndPos(_, token::Token::RBrace)) = token_iter.peek() {
+        token_iter.next();
+        return Ok(values);
+    }
+
+    loop {
+        values.push(parse_and_eval_expression(token_iter, context, input)?);
+
+        match token_iter.next() {
+            Some(&lexer::TokenAndPos(_, token::Token::Comma)) => continue,
+            Some(&lexer::TokenAndPos(_, token::Token::RBrace)) => break,
+            _ => return Err(Error::new(ErrorKind::Syntax, "Expected , or } in list".to_string())),
+        }
+    }
+
+    Ok(values)
+}
+
+fn parse_expression(
+    token_iter: &mut Peekable<Iter<'_, lexer::TokenAndPos>>,
+) -> Result<VecDeque<token::Token>, Error> {
+    let mut output_queue: VecDeque<token::Token> = VecDeque::new();
+    let mut operator_stack: Vec<token::Token> = Vec::new();
+    // Argument counts of the function calls currently open, innermost last
+    let mut arg_counts: Vec<usize> = Vec::new();
+    let mut last_was_lparen = false;
+
+    loop {
+        match token_iter.peek() {
+            Some(&&lexer::TokenAndPos(_, token::Token::Then)) |
+            Some(&&lexer::TokenAndPos(_, token::Token::To)) |
+            Some(&&lexer::TokenAndPos(_, token::Token::Semicolon)) |
+            Some(&&lexer::TokenAndPos(_, token::Token::Step)) |
+            Some(&&lexer::TokenAndPos(_, token::Token::Goto)) |
+            Some(&&lexer::TokenAndPos(_, token::Token::Gosub)) |
+            Some(&&lexer::TokenAndPos(_, token::Token::In)) |
+            Some(&&lexer::TokenAndPos(_, token::Token::RBrace)) |
+            Some(&&lexer::TokenAndPos(_, token::Token::Rem)) |
+            None => break,
+            // A comma or closing parenthesis outside of any parentheses ends the
+            // expression, e.g. items of a list or a subscript
+            Some(&&lexer::TokenAndPos(_, token::Token::Comma)) |
+            Some(&&lexer::TokenAndPos(_, token::Token::RParen))
+                if !operator_stack.contains(&token::Token::LParen) => break,
+            _ => {}
         }
 
-        _ => Err("Invalid expression!".to_string()),
+        let was_lparen = last_was_lparen;
+        last_was_lparen = false;
+
+        match token_iter.next() {
+            // Array element access works like a function call on the array's name
+            Some(lexer::TokenAndPos(_, token::Token::Variable(name)))
+                if matches!(token_iter.peek(), Some(&&lexer::TokenAndPos(_, token::Token::LParen))) =>
+            {
+                operator_stack.push(token::Token::Variable(name.clone()));
+                arg_counts.push(1);
+            }
+            Some(lexer::TokenAndPos(_, value_token)) if value_token.is_value() => {
+                output_queue.push_back(value_token.clone())
+            }
+            Some(lexer::TokenAndPos(_, op_token)) if op_token.is_operator() => {
+                // A prefix operator has no left operand, so nothing can be waiting on it
+                let associativity = op_token.operator_associavity().unwrap();
+
+                while let Some(top_op) = operator_stack.last() {
+                    if op_token.is_unary_operator() || !top_op.is_operator() {
+                        break;
+                    }
+
+                    if (associativity == token::Associativity::Left
+                        && op_token.operator_precedence() <= top_op.operator_precedence())
+                        || (associativity == token::Associativity::Right
+                            && op_token.operator_precedence() < top_op.operator_precedence())
+                    {
+                        output_queue.push_back(operator_stack.pop().unwrap());
+                    } else {
+                        break;
+                    }
+                }
+
+                operator_stack.push(op_token.clone());
+            }
+            Some(lexer::TokenAndPos(_, token::Token::Function(name))) => {
+                match token_iter.peek() {
+                    Some(&&lexer::TokenAndPos(_, token::Token::LParen)) => {}
+                    _ => return Err(Error::new(ErrorKind::Syntax, format!("Function {} must be followed by (", name))),
+                }
+
+                operator_stack.push(token::Token::Function(name.clone()));
+                arg_counts.push(1);
+            }
+            // The name is kept in an FnCall until its ) gives the argument count
+            Some(lexer::TokenAndPos(_, token::Token::Fn)) => {
+                match (token_iter.next(), token_iter.peek()) {
+                    (
+                        Some(lexer::TokenAndPos(_, token::Token::Variable(name))),
+                        Some(&&lexer::TokenAndPos(_, token::Token::LParen)),
+                    ) => {
+                        operator_stack.push(token::Token::FnCall(name.clone(), 0));
+                        arg_counts.push(1);
+                    }
+                    _ => return Err(Error::new(
+                        ErrorKind::Syntax,
+                        "FN must be followed by a function name and (".to_string(),
+                    )),
+                }
+            }
+            Some(&lexer::TokenAndPos(_, token::Token::LParen)) => {
+                operator_stack.push(token::Token::LParen);
+                last_was_lparen = true;
+            }
+            Some(&lexer::TokenAndPos(_, token::Token::Comma)) => {
+                while let Some(top_op) = operator_stack.last() {
+                    if *top_op == token::Token::LParen {
+                        break;
+                    }
+                    output_queue.push_back(operator_stack.pop().unwrap());
+                }
+
+                match operator_stack.len().checked_sub(2).map(|i| &operator_stack[i]) {
+                    Some(token::Token::Function(_)) | Some(token::Token::Variable(_)) | Some(token::Token::FnCall(..)) => {
+                        *arg_counts.last_mut().unwrap() += 1
+                    }
+                    _ => return Err(Error::new(ErrorKind::Syntax, "Unexpected comma in expression".to_string())),
+                }
+            }
+            Some(&lexer::TokenAndPos(_, token::Token::RParen)) => {
+                loop {
+                    match operator_stack.pop() {
+                        Some(token::Token::LParen) => break,
+                        Some(ref next_token) => output_queue.push_back(next_token.clone()),
+                        None => return Err(Error::new(
+                            ErrorKind::Syntax,
+                            "Mismatched parenthesis in expression".to_string(),
+                        )),
+                    }
+                }
+
+                match operator_stack.last() {
+                    Some(token::Token::Function(_)) | Some(token::Token::Variable(_)) | Some(token::Token::FnCall(..)) => {
+                        let argc = arg_counts.pop().unwrap();
+                        let argc = if was_lparen { 0 } else { argc };
+
+                        match operator_stack.pop() {
+                            Some(token::Token::Function(name)) => {
+                                output_queue.push_back(token::Token::Call(name, argc))
+                            }
+                            Some(token::Token::Variable(name)) => {
+                                output_queue.push_back(token::Token::Index(name, argc))
+                            }
+                            Some(token::Token::FnCall(name, _)) => {
+                                output_queue.push_back(token::Token::FnCall(name, argc))
+                            }
+                            _ => unreachable!(),
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            Some(lexer::TokenAndPos(_, other)) => {
+                return Err(Error::new(ErrorKind::Syntax, format!("Unexpected {:?} in expression", other)));
+            }
+            None => unreachable!(),
+        }
+    }
+
+    while !operator_stack.is_empty() {
+        match operator_stack.pop().unwrap() {
+            token::Token::LParen | token::Token::RParen => {
+                return Err(Error::new(ErrorKind::Syntax, "Mismatched parenthesis in expression.".to_string()))
+            }
+            op_token => output_queue.push_back(op_token.clone()),
+        }
+    }
+
+    Ok(output_queue)
+}
+
+// An expression, optionally followed by IN low TO high for an inclusive range check
+fn parse_and_eval_condition(
+    token_iter: &mut Peekable<Iter<'_, lexer::TokenAndPos>>,
+    context: &Context,
+    input: &mut dyn BufRead,
+) -> Result<value::Value, Error> {
+    let value = parse_and_eval_expression(token_iter, context, input)?;
+
+    if let Some(&&lexer::TokenAndPos(_, token::Token::In)) = token_iter.peek() {
+        token_iter.next();
+        let low = parse_and_eval_expression(token_iter, context, input)?;
+
+        match token_iter.next() {
+            Some(&lexer::TokenAndPos(_, token::Token::To)) => {}
+            _ => return Err(Error::new(ErrorKind::Syntax, "IN must be followed by a range: low TO high".to_string())),
+        }
+
+        let high = parse_and_eval_expression(token_iter, context, input)?;
+        return Ok(value::Value::Bool(value.gteq(&low)? && value.lteq(&high)?));
+    }
+
+    Ok(value)
+}
+
+fn parse_and_eval_expression<'a>(
+    token_iter: &mut Peekable<Iter<'a, lexer::TokenAndPos>>,
+    context: &Context,
+    input: &mut dyn BufRead,
+) -> Result<value::Value, Error> {
+    let output_queue = parse_expression(token_iter)?;
+    eval_expression(&output_queue, context, input)
+}
+
+// Evaluates an expression already in RPN form as produced by parse_expression
+fn eval_expression(
+    output_queue: &VecDeque<token::Token>,
+    context: &Context,
+    input: &mut dyn BufRead,
+) -> Result<value::Value, Error> {
+    eval_expression_in(output_queue, context, input, 0)
+}
+
+// `depth` counts the FN calls the expression is nested in
+fn eval_expression_in(
+    output_queue: &VecDeque<token::Token>,
+    context: &Context,
+    input: &mut dyn BufRead,
+    depth: usize,
+) -> Result<value::Value, Error> {
+    let mut stack: Vec<value::Value> = Vec::new();
+
+    for token in output_queue.iter() {
+        match token {
+            token::Token::Number(number) => {
+                stack.push(value::Value::from_number(*number))
+            }
+            token::Token::Err => stack.push(value::Value::Int(context.error_code.into())),
+            token::Token::Exitcode => stack.push(value::Value::Int(context.exit_code.into())),
+            token::Token::Erl => stack.push(value::Value::Int(context.error_line.0.into())),
+            token::Token::Curline => {
+                stack.push(value::Value::Int(context.current_line.0.into()))
+            }
+            token::Token::BString(bstring) => stack.push(value::Value::String(unescape(bstring))),
+            token::Token::Variable(name) => match context.lookup(name) {
+                Some(value) => stack.push(value),
+                None => {
+                    return Err(Error::new(ErrorKind::UndefinedVariable, format!(
+                        "Invalid variable reference {} in expression",
+                        name
+                    )))
+                }
+            },
+            token::Token::Index(name, argc) => {
+                if stack.len() < *argc {
+                    return Err(Error::new(ErrorKind::Syntax, format!("Array {} is missing subscripts", name)));
+                }
+
+                let subscripts = stack.split_off(stack.len() - *argc);
+                stack.push(context.get_element(name, &subscripts)?);
+            }
+            token::Token::Call(name, argc) => {
+                if stack.len() < *argc {
+                    return Err(Error::new(ErrorKind::Syntax, format!("Function {} is missing arguments", name)));
+                }
+
+                // Built-in functions see Ints as Numbers, those with whole results give Ints back
+                let args: Vec<_> = stack
+                    .split_off(stack.len() - *argc)
+                    .into_iter()
+                    .map(value::Value::into_float)
+                    .collect();
+                match call_builtin(name, &args, context, input) {
+                    Ok(value) => stack.push(value),
+                    Err(e) => return Err(e),
+                }
+            }
+            token::Token::FnCall(name, argc) => {
+                if stack.len() < *argc {
+                    return Err(Error::new(ErrorKind::Syntax, format!("FN {} is missing arguments", name)));
+                }
+
+                let args = stack.split_off(stack.len() - *argc);
+                stack.push(call_user_function(name, args, context, input, depth)?);
+            }
+            unary_token if unary_token.is_unary_operator() => {
+                if !stack.is_empty() {
+                    let value = stack.pop().unwrap();
+                    let result = match *unary_token {
+                        token::Token::UMinus => -value,
+                        token::Token::Bang => !value,
+                        // Pattern guard prevents any other match
+                        _ => unreachable!(),
+                    };
+                    match result {
+                        Ok(value) => stack.push(value),
+                        Err(e) => return Err(e),
+                    }
+                } else {
+                    return Err(Error::new(ErrorKind::Syntax, format!("Operator {:?} requires an operand!", unary_token)));
+                }
+            }
+            comparison_token if comparison_token.is_comparison_operator() => {
+                if stack.len() >= 2 {
+                    let operand2 = &stack.pop().unwrap();
+                    let operand1 = &stack.pop().unwrap();
+
+                    let result = match *comparison_token {
+                        token::Token::Equals => operand1.eq(operand2),
+                        token::Token::NotEqual => operand1.neq(operand2),
+                        token::Token::LessThan => operand1.lt(operand2),
+                        token::Token::GreaterThan => operand1.gt(operand2),
+                        token::Token::LessThanEqual => operand1.lteq(operand2),
+                        token::Token::GreaterThanEqual => operand1.gteq(operand2),
+                        // Pattern guard prevents any other match
+                        _ => unreachable!(),
+                    };
+                    match result {
+                        Ok(value) => stack.push(value::Value::Bool(value)),
+                        Err(e) => return Err(e),
+                    }
+                } else {
+                    return Err(Error::new(ErrorKind::Syntax, format!(
+                        "Comparison operator {:?} requires two operands",
+                        comparison_token
+                    )));
+                }
+            }
+            binary_op_token if binary_op_token.is_binary_operator() => {
+                if stack.len() >= 2 {
+                    let operand2 = stack.pop().unwrap();
+                    let operand1 = stack.pop().unwrap();
+
+                    let result = match *binary_op_token {
+                        token::Token::Plus => operand1 + operand2,
+                        token::Token::Minus => operand1 - operand2,
+                        token::Token::Ampersand => operand1.concat(operand2),
+                        token::Token::Multiply => operand1 * operand2,
+                        token::Token::Divide => operand1 / operand2,
+                        token::Token::IntDivide => operand1.int_div(operand2),
+                        token::Token::Mod => operand1.modulo(operand2, context.mod_mode),
+                        token::Token::Caret => operand1.pow(operand2),
+                        token::Token::And => operand1.and(operand2),
+                        token::Token::Or => operand1.or(operand2),
+                        // Pattern guard prevents any other match
+                        _ => unreachable!(),
+                    };
+                    match result {
+                        Ok(value) => stack.push(value),
+                        Err(e) => return Err(e),
+                    }
+                } else {
+                    return Err(Error::new(
+                        ErrorKind::Syntax,
+                        format!("Operator {:?} requires two operands", binary_op_token),
+                    ));
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    // If expression is well formed, there will only be the result on the stack
+    if stack.len() != 1 {
+        return Err(Error::new(ErrorKind::Syntax, "Cannot parse expression".to_string()));
+    }
+    
+    Ok(stack[0].clone())
+}
+
+fn call_user_function(
+    name: &str,
+    args: Vec<value::Value>,
+    context: &Context,
+    input: &mut dyn BufRead,
+    depth: usize,
+) -> Result<value::Value, Error> {
+    let function = match context.functions.get(name) {
+        Some(function) => function,
+        None => return Err(Error::new(ErrorKind::Syntax, format!("Undefined function FN {}", name))),
+    };
+    if args.len() != function.params.len() {
+        return Err(Error::new(ErrorKind::TypeMismatch, format!(
+            "FN {} expects {} argument(s) but got {}",
+            name, function.params.len(), args.len()
+        )));
+    }
+    if depth >= MAX_FN_DEPTH {
+        return Err(Error::new(ErrorKind::Syntax, format!("FN {} nested more than {} calls deep", name, MAX_FN_DEPTH)));
+    }
+
+    // Parameters live in a frame of their own, so FN F(X) leaves a global X alone
+    let mut frame = HashMap::new();
+    for (param, arg) in function.params.iter().zip(args) {
+        check_sigil(param, &arg)?;
+        frame.insert(param.clone(), arg);
+    }
+
+    context.scopes.borrow_mut().push(frame);
+    let result = eval_expression_in(&function.body, context, input, depth + 1);
+    context.scopes.borrow_mut().pop();
+    result
+}
+
+fn call_builtin(
+    name: &str,
+    args: &[value::Value],
+    context: &Context,
+    input: &mut dyn BufRead,
+) -> Result<value::Value, Error> {
+    match (name, args) {
+        ("EXIST", [value::Value::String(var)]) => {
+            Ok(value::Value::Bool(context.lookup(var).is_some()))
+        }
+        ("TYPEOF", [value::Value::String(var)]) => {
+            // Whole and fractional numbers are both "number", Int is only how a
+            // number happens to be stored
+            Ok(value::Value::String(match context.lookup(var) {
+                Some(value::Value::Int(_) | value::Value::Number(_)) => "number",
+                Some(value::Value::String(_)) => "string",
+                Some(value::Value::Bool(_)) => "bool",
+                Some(value::Value::Array { .. }) => "array",
+                None => "undefined",
+            }.to_string()))
+        }
+        ("BASE$", [value::Value::Number(number), value::Value::Number(radix)]) => {
+            if radix.fract() != 0.0 || *radix < 2.0 || *radix > 36.0 {
+                return Err(Error::new(
+                    ErrorKind::IllegalFunctionCall,
+                    format!("BASE$ radix must be a whole number from 2 to 36, got {}", radix),
+                ));
+            }
+            // i64::MAX as f64 rounds up to 2^63, which is already past i64
+            if number.fract() != 0.0 || number.abs() >= i64::MAX as f64 {
+                return Err(Error::new(
+                    ErrorKind::IllegalFunctionCall,
+                    format!("BASE$ can only convert whole numbers, got {}", number),
+                ));
+            }
+
+            let radix = *radix as u32;
+            let mut magnitude = (*number as i64).unsigned_abs();
+            let mut digits = Vec::new();
+            loop {
+                digits.push(std::char::from_digit((magnitude % radix as u64) as u32, radix)
+                    .unwrap()
+                    .to_ascii_uppercase());
+                magnitude /= radix as u64;
+                if magnitude == 0 {
+                    break;
+                }
+            }
+            if *number < 0.0 {
+                digits.push('-');
+            }
+
+            Ok(value::Value::String(digits.into_iter().rev().collect()))
+        }
+        ("BASE$", _) => Err(Error::new(ErrorKind::TypeMismatch, "BASE$ expects a number and a radix".to_string())),
+        // The argument is accepted for compatibility but doesn't change the result
+        ("RND", []) | ("RND", [value::Value::Number(_)]) => {
+            Ok(value::Value::Number(context.next_random()))
+        }
+        ("RND", _) => Err(Error::new(ErrorKind::TypeMismatch, "RND takes at most one numeric argument".to_string())),
+        // Stops short at end of input, so the result may have fewer than n characters
+        ("INPUT$", [value::Value::Number(count)]) => {
+            if count.fract() != 0.0 || *count < 0.0 {
+                return Err(Error::new(
+                    ErrorKind::IllegalFunctionCall,
+                    format!("INPUT$ needs a whole number of characters, got {}", count),
+                ));
+            }
+
+            match read_chars(input, *count as usize) {
+                Ok(input) => Ok(value::Value::String(input)),
+                Err(e) => Err(Error::new(ErrorKind::Io, format!("INPUT$ failed to read input: {}", e))),
+            }
+        }
+        ("INPUT$", _) => Err(Error::new(ErrorKind::TypeMismatch, "INPUT$ expects a number of characters".to_string())),
+        ("ABS", [value::Value::Number(number)]) => Ok(value::Value::from_number(number.abs())),
+        // Like classic BASIC, INT rounds down rather than towards zero
+        ("INT", [value::Value::Number(number)]) => Ok(value::Value::from_number(number.floor())),
+        ("SGN", [value::Value::Number(number)]) => Ok(value::Value::Int(if *number > 0.0 {
+            1
+        } else if *number < 0.0 {
+            -1
+        } else {
+            0
+        })),
+        ("SQR", [value::Value::Number(number)]) => {
+            if *number < 0.0 {
+                return Err(Error::new(ErrorKind::IllegalFunctionCall, format!("SQR of negative number {}", number)));
+            }
+            Ok(value::Value::Number(number.sqrt()))
+        }
+        // Positions are 1-based and counts past either end of the string are clamped
+        ("LEN", [value::Value::String(string)]) => Ok(value::Value::Int(string.chars().count() as i64)),
+        ("LEN", _) => Err(Error::new(ErrorKind::TypeMismatch, "LEN expects a string".to_string())),
+        ("LEFT$", [value::Value::String(string), value::Value::Number(count)]) => {
+            Ok(value::Value::String(string.chars().take(to_count(*count)).collect()))
+        }
+        ("RIGHT$", [value::Value::String(string), value::Value::Number(count)]) => {
+            let skip = string.chars().count().saturating_sub(to_count(*count));
+            Ok(value::Value::String(string.chars().skip(skip).collect()))
+        }
+        ("MID$", [value::Value::String(string), value::Value::Number(start)]) => {
+            let skip = to_count(*start).saturating_sub(1);
+            Ok(value::Value::String(string.chars().skip(skip).collect()))
+        }
+        ("MID$", [value::Value::String(string), value::Value::Number(start), value::Value::Number(count)]) => {
+            let skip = to_count(*start).saturating_sub(1);
+            Ok(value::Value::String(string.chars().skip(skip).take(to_count(*count)).collect()))
+        }
+        ("LEFT$", _) | ("RIGHT$", _) => Err(Error::new(
+            ErrorKind::TypeMismatch,
+            format!("{} expects a string and a count", name),
+        )),
+        ("MID$", _) => Err(Error::new(
+            ErrorKind::TypeMismatch,
+            "MID$ expects a string, a start and an optional length".to_string(),
+        )),
+        ("CHR$", [value::Value::Number(code)]) => {
+            match std::char::from_u32(*code as u32) {
+                Some(c) if code.fract() == 0.0 && *code >= 0.0 => Ok(value::Value::String(c.to_string())),
+                _ => Err(Error::new(
+                    ErrorKind::IllegalFunctionCall,
+                    format!("CHR$ of {} is not a valid character code", code),
+                )),
+            }
+        }
+        ("CHR$", _) => Err(Error::new(ErrorKind::TypeMismatch, "CHR$ expects a character code".to_string())),
+        // Only the first character counts
+        ("ASC", [value::Value::String(string)]) => match string.chars().next() {
+            Some(c) => Ok(value::Value::Int(c as u32 as i64)),
+            None => Err(Error::new(ErrorKind::IllegalFunctionCall, "ASC of an empty string".to_string())),
+        },
+        ("ASC", _) => Err(Error::new(ErrorKind::TypeMismatch, "ASC expects a string".to_string())),
+        // Unset variables read as an empty string
+        ("ENVIRON$", [value::Value::String(name)]) => Ok(value::Value::String(match context.environment {
+            Some(ref environment) => environment.get(name).cloned().unwrap_or_default(),
+            None => std::env::var(name).unwrap_or_default(),
+        })),
+        ("ENVIRON$", _) => Err(Error::new(ErrorKind::TypeMismatch, "ENVIRON$ expects a variable name".to_string())),
+        ("STR$", [value::Value::Number(number)]) => {
+            Ok(value::Value::String(format_number(*number, context.precision)))
+        }
+        ("STR$", _) => Err(Error::new(ErrorKind::TypeMismatch, "STR$ expects a number".to_string())),
+        ("VAL", [value::Value::String(string)]) => Ok(value::Value::from_number(leading_number(string))),
+        ("VAL", _) => Err(Error::new(ErrorKind::TypeMismatch, "VAL expects a string".to_string())),
+        // Angles are in radians
+        ("SIN", [value::Value::Number(number)]) => Ok(value::Value::Number(number.sin())),
+        ("COS", [value::Value::Number(number)]) => Ok(value::Value::Number(number.cos())),
+        ("TAN", [value::Value::Number(number)]) => Ok(value::Value::Number(number.tan())),
+        ("ATN", [value::Value::Number(number)]) => Ok(value::Value::Number(number.atan())),
+        ("EXP", [value::Value::Number(number)]) => Ok(value::Value::Number(number.exp())),
+        ("LOG", [value::Value::Number(number)]) => {
+            if *number <= 0.0 {
+                return Err(Error::new(ErrorKind::IllegalFunctionCall, format!("LOG of non-positive number {}", number)));
+            }
+            Ok(value::Value::Number(number.ln()))
+        }
+        ("ABS", _) | ("ATN", _) | ("COS", _) | ("EXP", _) | ("INT", _) | ("LOG", _) | ("SGN", _) |
+        ("SIN", _) | ("SQR", _) | ("TAN", _) => {
+            Err(Error::new(ErrorKind::TypeMismatch, format!("{} expects a single number", name)))
+        }
+        ("EXIST", _) | ("TYPEOF", _) => {
+            Err(Error::new(ErrorKind::TypeMismatch, format!("{} expects a variable name as a string", name)))
+        }
+        _ => Err(Error::new(ErrorKind::Syntax, format!("Unknown function {}", name))),
+    }
+}
+
+// The number at the start of a string as read by VAL: "12abc" is 12, and a
+// string that doesn't start with a number is 0
+fn leading_number(string: &str) -> f64 {
+    let trimmed = string.trim_start();
+    let chars: Vec<char> = trimmed.chars().collect();
+    let mut end = 0;
+
+    if matches!(chars.first(), Some('+') | Some('-')) {
+        end += 1;
+    }
+    while end < chars.len() && chars[end].is_ascii_digit() {
+        end += 1;
+    }
+    if chars.get(end) == Some(&'.') {
+        end += 1;
+        while end < chars.len() && chars[end].is_ascii_digit() {
+            end += 1;
+        }
+    }
+    // Only take the exponent if digits follow it
+    if matches!(chars.get(end), Some('e') | Some('E')) {
+        let mut exponent_end = end + 1;
+        if matches!(chars.get(exponent_end), Some('+') | Some('-')) {
+            exponent_end += 1;
+        }
+        if chars.get(exponent_end).is_some_and(|c| c.is_ascii_digit()) {
+            end = exponent_end;
+            while end < chars.len() && chars[end].is_ascii_digit() {
+                end += 1;
+            }
+        }
+    }
+
+    let prefix: String = chars[..end].iter().collect();
+    f64::from_str(&prefix).unwrap_or(0.0)
+}
+
+// Prints without a newline, keeping track of the column for PRINT zones
+fn print_text(context: &mut Context, output: &mut dyn Write, text: &str) -> io::Result<()> {
+    output.write_all(text.as_bytes())?;
+
+    context.column = match text.rfind('\n') {
+        Some(newline) => text[newline + 1..].chars().count(),
+        None => context.column + text.chars().count(),
+    };
+
+    Ok(())
+}
+
+// Text PRINT shows for a value, following the precision and boolean settings
+fn format_value(value: &value::Value, context: &Context) -> String {
+    match *value {
+        value::Value::Number(number) => format_number(number, context.precision),
+        ref value => value.display(context.bool_mode),
+    }
+}
+
+fn format_number(number: f64, precision: Option<usize>) -> String {
+    match precision {
+        Some(digits) => value::format_number_to(number, digits),
+        None => value::format_number(number),
+    }
+}
+
+// Character counts given to string functions, negative counts mean none
+fn to_count(number: f64) -> usize {
+    if number > 0.0 {
+        number as usize
+    } else {
+        0
+    }
+}
+
+// Reads up to `count` UTF-8 characters without waiting for the end of a line
+fn read_chars(reader: &mut dyn BufRead, count: usize) -> io::Result<String> {
+    let mut input = String::new();
+    let mut bytes = reader.bytes();
+
+    for _ in 0..count {
+        let first = match bytes.next() {
+            Some(byte) => byte?,
+            None => break,
+        };
+
+        let mut encoded = vec![first];
+        let width = match first {
+            0xf0..=0xff => 4,
+            0xe0..=0xef => 3,
+            0xc0..=0xdf => 2,
+            _ => 1,
+        };
+        for _ in 1..width {
+            match bytes.next() {
+                Some(byte) => encoded.push(byte?),
+                None => break,
+            }
+        }
+
+        input.push_str(&String::from_utf8_lossy(&encoded));
+    }
+
+    Ok(input)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io::Cursor,
+        sync::{Arc, Mutex},
+    };
+
+    fn run_with_input(source: &str, input: &str) -> (Result<String, EvalError>, String) {
+        let code_lines = lexer::tokenize_program(source).expect("program should lex");
+        let mut interpreter = Interpreter::new();
+        let mut output = Vec::new();
+        let result = interpreter.run_with_io(&code_lines, &mut Cursor::new(input), &mut output);
+        (result, String::from_utf8(output).unwrap())
+    }
+
+    fn error_kind(source: &str, input: &str) -> ErrorKind {
+        run_with_input(source, input).0.expect_err("program should fail").kind
+    }
+
+    #[test]
+    fn error_kind_ignores_program_strings() {
+        assert_eq!(error_kind("10 LET A = \"Out of DATA\"\n", ""), ErrorKind::TypeMismatch);
+        assert_eq!(error_kind("10 INPUT A\n", "Division by zero\n"), ErrorKind::TypeMismatch);
+    }
+
+    #[test]
+    fn error_kind_is_set_where_raised() {
+        assert_eq!(error_kind("10 PRINT 1 / 0\n", ""), ErrorKind::DivisionByZero);
+        assert_eq!(error_kind("10 PRINT \"a\" ^ 2\n", ""), ErrorKind::TypeMismatch);
+        assert_eq!(error_kind("10 READ A\n", ""), ErrorKind::OutOfData);
+        assert_eq!(error_kind("10 PRINT 1\n10 PRINT 2\n", ""), ErrorKind::Syntax);
+    }
+
+    #[test]
+    fn trapped_error_code_comes_from_kind() {
+        let source = "10 ON ERROR GOTO 100\n20 LET A = \"Division by zero\"\n30 END\n100 PRINT ERR\n";
+        let (result, output) = run_with_input(source, "");
+        assert!(result.is_ok());
+        assert_eq!(output, "13\n");
+    }
+
+    #[test]
+    fn next_inside_if_runs() {
+        let source = "10 FOR I = 1 TO 3\n20 IF I < 3 THEN NEXT I\n30 PRINT I\n40 NEXT I\n";
+        let (result, output) = run_with_input(source, "");
+        assert!(result.is_ok());
+        assert_eq!(output, "3\n");
+    }
+
+    #[test]
+    fn next_reached_by_jumping_past_another_runs() {
+        let source = "10 FOR I = 1 TO 3\n20 IF I = 2 THEN GOTO 50\n30 PRINT \"a\"; I\n40 NEXT I\n45 END\n\
+                      50 PRINT \"b\"; I\n60 NEXT I\n";
+        let (result, output) = run_with_input(source, "");
+        assert!(result.is_ok());
+        assert_eq!(output, "a1\nb2\na3\n");
+    }
+
+    // Output of the second program only, run on the interpreter the first one left behind
+    fn run_twice(first: &str, second: &str) -> (Result<String, EvalError>, String) {
+        let mut interpreter = Interpreter::new();
+        let first = lexer::tokenize_program(first).expect("program should lex");
+        let _ = interpreter.run_with_io(&first, &mut Cursor::new(""), &mut Vec::new());
+
+        let second = lexer::tokenize_program(second).expect("program should lex");
+        let mut output = Vec::new();
+        let result = interpreter.run_with_io(&second, &mut Cursor::new(""), &mut output);
+        (result, String::from_utf8(output).unwrap())
+    }
+
+    #[test]
+    fn new_run_forgets_gosub_of_previous_run() {
+        let (result, output) = run_twice("10 GOSUB 100\n100 END\n", "10 RETURN\n20 PRINT \"after return\"\n");
+        assert_eq!(result.unwrap_err().kind, ErrorKind::ReturnWithoutGosub);
+        assert_eq!(output, "");
+    }
+
+    #[test]
+    fn new_run_forgets_locals_of_previous_run() {
+        let first = "10 GOSUB 100\n100 LOCAL X\n110 LET X = 42\n120 END\n";
+        let (result, output) = run_twice(first, "10 PRINT X\n");
+        assert_eq!(result.unwrap_err().kind, ErrorKind::UndefinedVariable);
+        assert_eq!(output, "");
+    }
+
+    #[test]
+    fn new_run_forgets_loops_of_previous_run() {
+        let (result, _) = run_twice("10 FOR I = 1 TO 3\n20 END\n", "10 NEXT I\n");
+        assert_eq!(result.unwrap_err().kind, ErrorKind::NextWithoutFor);
+    }
+
+    #[test]
+    fn new_run_forgets_error_handler_of_previous_run() {
+        let (result, output) = run_twice("10 ON ERROR GOTO 20\n20 END\n", "10 PRINT 1 / 0\n20 PRINT \"handled\"\n");
+        assert_eq!(result.unwrap_err().kind, ErrorKind::DivisionByZero);
+        assert_eq!(output, "");
+    }
+
+    fn random_numbers(seed: u64) -> String {
+        let code_lines = lexer::tokenize_program("10 FOR I = 1 TO 100\n20 PRINT RND()\n30 NEXT I\n").unwrap();
+        let mut interpreter = Interpreter::new();
+        interpreter.set_seed(seed);
+        let mut output = Vec::new();
+        interpreter.run_with_io(&code_lines, &mut Cursor::new(""), &mut output).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn interpreters_on_threads_stay_independent() {
+        let expected: Vec<String> = (1..=4).map(random_numbers).collect();
+
+        let threads: Vec<_> = (1..=4)
+            .map(|seed| std::thread::spawn(move || (0..20).map(|_| random_numbers(seed)).collect::<Vec<_>>()))
+            .collect();
+
+        for (thread, expected) in threads.into_iter().zip(expected.iter()) {
+            for output in thread.join().unwrap() {
+                assert_eq!(&output, expected);
+            }
+        }
+        assert_ne!(expected[0], expected[1]);
+    }
+
+    #[test]
+    fn goto_last_line_runs_it_then_ends() {
+        let (result, output) = run_with_input("10 GOTO 30\n20 PRINT \"skipped\"\n30 PRINT \"last\"\n", "");
+        assert!(result.is_ok());
+        assert_eq!(output, "last\n");
+    }
+
+    #[test]
+    fn jump_backward_from_last_line() {
+        let source = "10 LET N = 0\n20 LET N = N + 1\n30 PRINT N\n40 IF N < 3 THEN 20\n";
+        let (result, output) = run_with_input(source, "");
+        assert!(result.is_ok());
+        assert_eq!(output, "1\n2\n3\n");
+
+        let source = "10 LET N = 0\n20 LET N = N + 1\n30 IF N = 3 THEN 50\n40 GOTO 20\n50 PRINT N\n";
+        let (result, output) = run_with_input(source, "");
+        assert!(result.is_ok());
+        assert_eq!(output, "3\n");
+    }
+
+    #[test]
+    fn read_converts_data_to_variable_type() {
+        let source = "10 DATA 12, hello, \"3.5\", 7\n20 READ A, B$, C, D$\n30 PRINT A + C; B$; D$ + \"!\"\n";
+        let (result, output) = run_with_input(source, "");
+        assert!(result.is_ok());
+        assert_eq!(output, "15.5hello7!\n");
+    }
+
+    #[test]
+    fn read_of_word_into_number_is_data_error() {
+        let (result, _) = run_with_input("10 DATA hello\n20 READ A\n", "");
+        let error = result.unwrap_err();
+        assert_eq!(error.message, "Syntax error in DATA");
+        assert_eq!(error.kind, ErrorKind::Syntax);
+        assert_eq!(error.line, lexer::LineNumber(10));
+    }
+
+    #[test]
+    fn dim_too_large_is_an_error() {
+        let sources = [
+            "10 DIM A(1E12)\n",
+            "10 DIM A(9223372036854775807)\n",
+            "10 DIM A(4294967296, 4294967296)\n",
+            "10 DIM A(3000, 3000)\n",
+        ];
+        for source in sources.iter() {
+            assert_eq!(error_kind(source, ""), ErrorKind::IllegalFunctionCall);
+        }
+        assert!(run_with_input("10 DIM A(1000)\n20 DIM B(100, 100)\n", "").0.is_ok());
+    }
+
+    #[test]
+    fn screen_too_large_is_an_error() {
+        assert_eq!(error_kind("10 SCREEN 4294967296, 4294967296\n", ""), ErrorKind::IllegalFunctionCall);
+        assert_eq!(error_kind("10 SCREEN 100000, 100000\n", ""), ErrorKind::IllegalFunctionCall);
+        assert!(run_with_input("10 SCREEN 80, 25\n", "").0.is_ok());
+    }
+
+    #[test]
+    fn huge_print_spacing_is_an_error() {
+        assert_eq!(error_kind("10 PRINT SPC(1E18)\n", ""), ErrorKind::IllegalFunctionCall);
+        assert_eq!(error_kind("10 PRINT TAB(1E18)\n", ""), ErrorKind::IllegalFunctionCall);
+        let (result, output) = run_with_input("10 PRINT SPC(2); \"x\"; TAB(6); \"y\"\n", "");
+        assert!(result.is_ok());
+        assert_eq!(output, "  x  y\n");
+    }
+
+    #[test]
+    fn assignment_follows_sigil() {
+        assert!(run_with_input("10 LET A$ = \"x\"\n", "").0.is_ok());
+        assert_eq!(error_kind("10 LET A$ = 5\n", ""), ErrorKind::TypeMismatch);
+    }
+
+    #[test]
+    fn dim_initial_values_follow_sigil() {
+        assert_eq!(error_kind("10 DIM A(1) = {\"x\", \"y\"}\n", ""), ErrorKind::TypeMismatch);
+        assert_eq!(error_kind("10 DIM A$(1) = {1, 2}\n", ""), ErrorKind::TypeMismatch);
+        let (result, output) = run_with_input("10 DIM A$(1) = {\"x\", \"y\"}\n20 DIM B(1) = {1, 2}\n30 PRINT A$(1); B(1)\n", "");
+        assert!(result.is_ok());
+        assert_eq!(output, "y2\n");
+    }
+
+    #[test]
+    fn typeof_names_each_type() {
+        let source = "10 LET A = 1\n20 LET B = 1.5\n30 LET C$ = \"x\"\n40 LET D = 1 < 2\n50 DIM E(2)\n\
+                      60 PRINT TYPEOF(\"A\"); \" \"; TYPEOF(\"B\"); \" \"; TYPEOF(\"C$\"); \" \"; TYPEOF(\"D\"); \" \"; \
+                      TYPEOF(\"E\"); \" \"; TYPEOF(\"F\")\n";
+        let (result, output) = run_with_input(source, "");
+        assert!(result.is_ok());
+        assert_eq!(output, "number number string bool array undefined\n");
+    }
+
+    #[test]
+    fn swap_exchanges_values_of_same_sigil() {
+        let source = "10 LET A = 1\n20 LET B = 2.5\n30 LET A$ = \"x\"\n40 LET B$ = \"y\"\n\
+                      50 SWAP A, B\n60 SWAP A$, B$\n70 PRINT A; B; A$; B$\n";
+        let (result, output) = run_with_input(source, "");
+        assert!(result.is_ok());
+        assert_eq!(output, "2.51yx\n");
+    }
+
+    #[test]
+    fn swap_of_number_and_string_is_type_mismatch() {
+        let source = "10 ON ERROR GOTO 100\n20 LET A = 1\n30 LET B$ = \"x\"\n40 SWAP A, B$\n\
+                      100 PRINT ERR; A; B$\n";
+        let (result, output) = run_with_input(source, "");
+        assert!(result.is_ok());
+        assert_eq!(output, "131x\n");
+    }
+
+    #[test]
+    fn swap_with_array_is_type_mismatch() {
+        let source = "10 DIM A(3)\n20 LET B = 1\n30 SWAP A, B\n";
+        let error = run_with_input(source, "").0.unwrap_err();
+        assert_eq!(error.kind, ErrorKind::TypeMismatch);
+        assert_eq!(error.message, "Type mismatch, A is an array and can't be swapped");
+
+        let (result, output) = run_with_input("10 DIM A(3)\n20 LET B = 1\n30 ON ERROR GOTO 50\n40 SWAP B, A\n50 PRINT B\n", "");
+        assert!(result.is_ok());
+        assert_eq!(output, "1\n");
+    }
+
+    #[test]
+    fn swap_with_undefined_variable_fails() {
+        assert_eq!(error_kind("10 LET A = 1\n20 SWAP A, B\n", ""), ErrorKind::UndefinedVariable);
+    }
+
+    #[test]
+    fn for_past_its_end_runs_zero_times() {
+        let source = "10 FOR I = 1 TO 10 STEP -1\n20 PRINT \"up\"\n30 NEXT I\n\
+                      40 FOR J = 10 TO 1 STEP 2\n50 PRINT \"down\"\n60 NEXT\n70 PRINT I; J\n";
+        let (result, output) = run_with_input(source, "");
+        assert!(result.is_ok());
+        assert_eq!(output, "110\n");
+    }
+
+    #[test]
+    fn for_counts_by_its_step_sign() {
+        let (result, output) = run_with_input("10 FOR I = 10 TO 1 STEP -2\n20 PRINT I;\n30 NEXT I\n40 PRINT\n", "");
+        assert!(result.is_ok());
+        assert_eq!(output, "108642\n");
+
+        let (result, output) = run_with_input("10 FOR I = 5 TO 1\n20 PRINT I\n30 NEXT I\n40 PRINT \"after\"; I\n", "");
+        assert!(result.is_ok());
+        assert_eq!(output, "after5\n");
+    }
+
+    #[test]
+    fn skipped_for_jumps_past_nested_loops() {
+        let source = "10 FOR I = 3 TO 1 STEP 1\n20 FOR J = 1 TO 2\n30 PRINT J\n40 NEXT J\n\
+                      50 NEXT I\n60 PRINT \"done\"\n";
+        let (result, output) = run_with_input(source, "");
+        assert!(result.is_ok());
+        assert_eq!(output, "done\n");
+    }
+
+    #[test]
+    fn skipped_for_without_next_fails() {
+        assert_eq!(error_kind("10 FOR I = 1 TO 0 STEP 1\n20 PRINT I\n", ""), ErrorKind::Syntax);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn shell_stderr_goes_to_program_output() {
+        let code_lines = lexer::tokenize_program("10 SHELL \"echo out; echo err 1>&2\"\n").unwrap();
+        let mut interpreter = Interpreter::new();
+        interpreter.set_allow_shell(true);
+        let mut output = Vec::new();
+        let result = interpreter.run_with_io(&code_lines, &mut Cursor::new(""), &mut output);
+        assert!(result.is_ok());
+        assert_eq!(String::from_utf8(output).unwrap(), "out\nerr\n");
+    }
+
+    // An output the test can still read once the interpreter owns its copy
+    #[derive(Clone, Default)]
+    struct SharedOutput(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedOutput {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn builder_sets_input_and_output() {
+        let code_lines = lexer::tokenize_program("10 INPUT A$\n20 PRINT \"got \"; A$\n").unwrap();
+        let output = SharedOutput::default();
+        let mut interpreter = Interpreter::builder()
+            .input(Cursor::new("one\ntwo\n"))
+            .output(output.clone())
+            .build();
+
+        assert!(interpreter.run(&code_lines).is_ok());
+        assert!(interpreter.run(&code_lines).is_ok());
+        assert_eq!(String::from_utf8(output.0.lock().unwrap().clone()).unwrap(), "got one\ngot two\n");
+    }
+
+    #[test]
+    fn empty_numbered_line_counts_as_duplicate() {
+        let error = run_with_input("10 PRINT 1\n10\n20 PRINT 2\n", "").0.unwrap_err();
+        assert_eq!(error.message, "Line number 10 is used twice, by program lines 1 and 2");
+    }
+
+    #[test]
+    fn direct_statement_keeps_stopped_program_resumable() {
+        let code_lines = lexer::tokenize_program("10 FOR I = 1 TO 2\n20 LET X = I * 10\n30 STOP\n40 NEXT I\n50 PRINT \"done\"; X\n").unwrap();
+        let mut interpreter = Interpreter::new();
+        let mut output = Vec::new();
+
+        assert!(interpreter.run_with_io(&code_lines, &mut Cursor::new(""), &mut output).is_ok());
+        let direct = lexer::tokenize_unnumbered_line("PRINT X", lexer::LineNumber(0)).unwrap();
+        assert!(interpreter.execute_direct_with_io(&direct, &mut Cursor::new(""), &mut output).is_ok());
+        assert!(interpreter.cont_with_io(&code_lines, &mut Cursor::new(""), &mut output).is_ok());
+        assert!(interpreter.execute_direct_with_io(&direct, &mut Cursor::new(""), &mut output).is_ok());
+        assert!(interpreter.cont_with_io(&code_lines, &mut Cursor::new(""), &mut output).is_ok());
+
+        assert_eq!(String::from_utf8(output).unwrap(), "\nBreak in 30\n10\n\nBreak in 30\n20\ndone20\n");
+    }
+
+    #[test]
+    fn restored_snapshot_continues_identically() {
+        let source = "5 LET T = 0\n10 FOR I = 1 TO 4\n20 LET T = T + I\n30 IF I = 2 THEN STOP\n40 PRINT I; T; RND()\n50 NEXT I\n";
+        let code_lines = lexer::tokenize_program(source).unwrap();
+        let mut interpreter = Interpreter::builder().seed(7).build();
+        interpreter.run_with_io(&code_lines, &mut Cursor::new(""), &mut Vec::new()).unwrap();
+        let snapshot = interpreter.snapshot();
+
+        let mut first = Vec::new();
+        interpreter.cont_with_io(&code_lines, &mut Cursor::new(""), &mut first).unwrap();
+        interpreter.restore(&snapshot);
+        let mut second = Vec::new();
+        interpreter.cont_with_io(&code_lines, &mut Cursor::new(""), &mut second).unwrap();
+
+        assert!(String::from_utf8(first.clone()).unwrap().starts_with("23"));
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn on_error_reports_classic_codes() {
+        let handler = "100 PRINT ERR; ERL\n110 RESUME NEXT\n";
+        let (result, output) = run_with_input(&format!("10 ON ERROR GOTO 100\n20 PRINT 1 / 0\n30 END\n{}", handler), "");
+        assert!(result.is_ok());
+        assert_eq!(output, "1120\n");
+
+        let (result, output) = run_with_input(&format!("10 ON ERROR GOTO 100\n20 DIM A(2)\n30 PRINT A(5)\n40 END\n{}", handler), "");
+        assert!(result.is_ok());
+        assert_eq!(output, "930\n");
+    }
+
+    #[test]
+    fn on_error_to_missing_line_fails() {
+        let error = run_with_input("10 ON ERROR GOTO 999\n20 PRINT 1 / 0\n", "").0.unwrap_err();
+        assert_eq!(error.kind, ErrorKind::BadGoto);
+        assert_eq!(error.line, lexer::LineNumber(10));
+        assert_eq!(error.message, "Invalid target line for ON ERROR, there is no line 999");
+    }
+
+    #[test]
+    fn base_converts_to_any_radix() {
+        let (result, output) = run_with_input("10 PRINT BASE$(10, 2); \" \"; BASE$(255, 16); \" \"; BASE$(-35, 36)\n", "");
+        assert!(result.is_ok());
+        assert_eq!(output, "1010 FF -Z\n");
+    }
+
+    #[test]
+    fn base_rejects_bad_arguments() {
+        for source in ["10 PRINT BASE$(10, 1)\n", "10 PRINT BASE$(10, 37)\n", "10 PRINT BASE$(1.5, 2)\n", "10 PRINT BASE$(2 ^ 63, 2)\n"] {
+            assert_eq!(error_kind(source, ""), ErrorKind::IllegalFunctionCall, "{}", source);
+        }
+        assert_eq!(error_kind("10 PRINT BASE$(\"10\", 2)\n", ""), ErrorKind::TypeMismatch);
+    }
+
+    #[test]
+    fn fn_parameters_and_locals_stay_local() {
+        let source = "10 LET X = 1\n20 DEF FN DOUBLE(X) = X * 2\n30 PRINT FN DOUBLE(21); X\n\
+                      40 GOSUB 100\n50 PRINT X; EXIST(\"Y\")\n60 END\n\
+                      100 LOCAL X, Y\n110 LET X = 7\n120 LET Y = 8\n130 PRINT X; Y\n140 RETURN\n";
+        let (result, output) = run_with_input(source, "");
+        assert!(result.is_ok());
+        assert_eq!(output, "421\n78\n1false\n");
+    }
+
+    #[test]
+    fn def_fn_with_one_and_two_arguments() {
+        let source = "10 DEF FN SQ(X) = X * X\n20 DEF FN HYP(A, B) = SQR(FN SQ(A) + FN SQ(B))\n30 PRINT FN SQ(4); FN HYP(3, 4)\n";
+        let (result, output) = run_with_input(source, "");
+        assert!(result.is_ok());
+        assert_eq!(output, "165\n");
+    }
+
+    #[test]
+    fn undefined_fn_fails() {
+        let error = run_with_input("10 PRINT FN NOPE(1)\n", "").0.unwrap_err();
+        assert!(error.message.contains("Undefined function FN NOPE"), "{}", error.message);
+    }
+
+    fn run_with_mod_mode(source: &str, mode: value::ModMode) -> String {
+        let code_lines = lexer::tokenize_program(source).unwrap();
+        let mut interpreter = Interpreter::builder().mod_mode(mode).build();
+        let mut output = Vec::new();
+        interpreter.run_with_io(&code_lines, &mut Cursor::new(""), &mut output).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn mod_sign_follows_the_mode() {
+        let source = "10 PRINT (-7) MOD 3; \" \"; 7 MOD -3\n";
+        assert_eq!(run_with_mod_mode(source, value::ModMode::Truncated), "-1 1\n");
+        assert_eq!(run_with_mod_mode(source, value::ModMode::Floored), "2 -2\n");
+    }
+
+    #[test]
+    fn percent_is_mod_and_mod_by_zero_fails() {
+        let source = "10 PRINT (-7) % 3; \" \"; 7 % -3\n";
+        assert_eq!(run_with_mod_mode(source, value::ModMode::Truncated), "-1 1\n");
+        assert_eq!(run_with_mod_mode(source, value::ModMode::Floored), "2 -2\n");
+        assert_eq!(error_kind("10 PRINT 7 MOD 0\n", ""), ErrorKind::DivisionByZero);
+        assert_eq!(error_kind("10 PRINT 7 % 0\n", ""), ErrorKind::DivisionByZero);
+    }
+
+    #[test]
+    fn division_by_zero_stops_the_program() {
+        let error = run_with_input("10 LET A = 0\n20 PRINT 0 / A\n30 PRINT \"unreached\"\n", "").0.unwrap_err();
+        assert_eq!(error.kind, ErrorKind::DivisionByZero);
+        assert_eq!(error.line, lexer::LineNumber(20));
+        assert_eq!(error_kind("10 LET B = 1 / 0\n", ""), ErrorKind::DivisionByZero);
+    }
+
+    #[test]
+    fn fractional_line_numbers_are_rejected() {
+        let error = run_with_input("10 GOTO 10.5\n", "").0.unwrap_err();
+        assert_eq!(error.kind, ErrorKind::BadGoto);
+        assert_eq!(error.message, "Invalid GOTO target: Line number must be a whole number, got 10.5");
+
+        let error = run_with_input("10 LET A = 10.5\n20 GOSUB 0 + A\n", "").0.unwrap_err();
+        assert!(error.message.ends_with("Line number must be a whole number, got 10.5"), "{}", error.message);
+    }
+
+    #[test]
+    fn line_numbers_outside_u32_are_rejected() {
+        let error = run_with_input("10 GOTO 4294967296\n", "").0.unwrap_err();
+        assert_eq!(error.kind, ErrorKind::BadGoto);
+        assert_eq!(error.message, "Invalid GOTO target: Line number out of range, 4294967296 is not between 0 and 4294967295");
+
+        let error = run_with_input("10 GOSUB 0 - 3\n", "").0.unwrap_err();
+        assert_eq!(error.kind, ErrorKind::BadGoto);
+        assert!(error.message.ends_with("Line number out of range, -3 is not between 0 and 4294967295"), "{}", error.message);
+
+        // A literal GOTO target can't have a sign at all
+        let error = run_with_input("10 GOTO -3\n", "").0.unwrap_err();
+        assert_eq!(error.message, "GOTO must be followed by a valid line number or label");
+    }
+
+    #[test]
+    fn step_limit_stops_an_endless_loop() {
+        let code_lines = lexer::tokenize_program("10 GOTO 10\n").unwrap();
+        let mut interpreter = Interpreter::builder().step_limit(Some(1000)).build();
+        let error = interpreter.run_with_io(&code_lines, &mut Cursor::new(""), &mut Vec::new()).unwrap_err();
+        assert_eq!(error.kind, ErrorKind::Limit);
+        assert_eq!(error.message, "Execution step limit exceeded");
+    }
+
+    #[test]
+    fn timeout_stops_an_endless_loop() {
+        let code_lines = lexer::tokenize_program("10 GOTO 10\n").unwrap();
+        let mut interpreter = Interpreter::builder().timeout(Some(Duration::from_millis(50))).build();
+        let error = interpreter.run_with_io(&code_lines, &mut Cursor::new(""), &mut Vec::new()).unwrap_err();
+        assert_eq!(error.kind, ErrorKind::Limit);
+        assert_eq!(error.message, "Execution timed out");
+    }
+
+    #[test]
+    fn tab_behind_the_cursor_starts_a_new_line() {
+        let (result, output) = run_with_input("10 PRINT \"HELLO\"; TAB(3); \"X\"; TAB(6); \"Y\"\n", "");
+        assert!(result.is_ok());
+        assert_eq!(output, "HELLO\n  X  Y\n");
+    }
+
+    #[test]
+    fn shell_is_refused_unless_allowed() {
+        let error = run_with_input("10 SHELL \"echo hi\"\n", "").0.unwrap_err();
+        assert_eq!(error.kind, ErrorKind::IllegalFunctionCall);
+        assert_eq!(error.message, "SHELL not permitted");
+    }
+
+    #[test]
+    fn builder_applies_each_option() {
+        let mut interpreter = Interpreter::builder()
+            .numbering(lexer::Numbering::Sequential)
+            .precision(Some(3))
+            .bool_mode(value::BoolMode::Numeric)
+            .allow_shell(true)
+            .step_limit(Some(4))
+            .build();
+
+        let source = "PRINT 1 / 3\nPRINT 1 < 2\nSHELL \"exit 3\"\nPRINT EXITCODE\n";
+        let code_lines = lexer::tokenize_program_with(source, lexer::Numbering::Sequential).unwrap();
+        let mut output = Vec::new();
+        assert!(interpreter.run_with_io(&code_lines, &mut Cursor::new(""), &mut output).is_ok());
+        assert_eq!(String::from_utf8(output).unwrap(), "0.333\n-1\n3\n");
+
+        let code_lines = lexer::tokenize_program_with("PRINT 1\nGOTO 10\n", lexer::Numbering::Sequential).unwrap();
+        let error = interpreter.run_with_io(&code_lines, &mut Cursor::new(""), &mut Vec::new()).unwrap_err();
+        assert_eq!(error.message, "Jumps to line numbers aren't allowed in a program without line numbers");
+
+        let code_lines = lexer::tokenize_program_with("LET A = 1\nWHILE 1 < 2\nWEND\n", lexer::Numbering::Sequential).unwrap();
+        let error = interpreter.run_with_io(&code_lines, &mut Cursor::new(""), &mut Vec::new()).unwrap_err();
+        assert_eq!(error.message, "Execution step limit exceeded");
+    }
+
+    #[test]
+    fn computed_gosub_targets() {
+        let source = "10 LET N = 2\n20 GOSUB 100 * N\n30 PRINT \"back\"\n40 END\n200 PRINT \"at\"; CURLINE\n210 RETURN\n";
+        let (result, output) = run_with_input(source, "");
+        assert!(result.is_ok());
+        assert_eq!(output, "at200\nback\n");
+
+        let error = run_with_input("10 LET N = 3\n20 GOSUB 100 * N\n", "").0.unwrap_err();
+        assert_eq!(error.kind, ErrorKind::BadGoto);
+        assert_eq!(error.message, "GOSUB to missing line 300");
+
+        assert_eq!(error_kind("10 GOSUB 100 * 0\n", ""), ErrorKind::BadGoto);
+    }
+
+    #[test]
+    fn on_gosub_picks_a_target_or_falls_through() {
+        let program = |selector: &str| {
+            format!("10 ON {} GOSUB 100, 200\n20 PRINT \"next\"\n30 END\n100 PRINT \"one\"\n110 RETURN\n200 PRINT \"two\"\n210 RETURN\n", selector)
+        };
+        for (selector, expected) in [("2", "two\nnext\n"), ("1.9", "one\nnext\n"), ("0", "next\n"), ("3", "next\n"), ("-1", "next\n")] {
+            let (result, output) = run_with_input(&program(selector), "");
+            assert!(result.is_ok(), "ON {}", selector);
+            assert_eq!(output, expected, "ON {}", selector);
+        }
+    }
+
+    #[test]
+    fn on_goto_picks_a_target_or_falls_through() {
+        let program = |selector: &str| {
+            format!("10 ON {} GOTO 100, done\n20 PRINT \"next\"\n30 END\n100 PRINT \"one\"\n110 END\n200 done: PRINT \"label\"\n", selector)
+        };
+        for (selector, expected) in [("1", "one\n"), ("2", "label\n"), ("0", "next\n"), ("3", "next\n")] {
+            let (result, output) = run_with_input(&program(selector), "");
+            assert!(result.is_ok(), "ON {}", selector);
+            assert_eq!(output, expected, "ON {}", selector);
+        }
+
+        // A missing target is only an error once it is picked
+        let source = "10 ON N GOTO 100, 999\n20 PRINT \"next\"\n100 END\n";
+        assert!(run_with_input(&format!("5 LET N = 1\n{}", source), "").0.is_ok());
+        let error = run_with_input(&format!("5 LET N = 2\n{}", source), "").0.unwrap_err();
+        assert_eq!(error.kind, ErrorKind::BadGoto);
+        assert_eq!(error.message, "Invalid target for ON GOTO: missing line 999");
+    }
+
+    #[test]
+    fn exist_checks_for_a_variable() {
+        let (result, output) = run_with_input("10 LET A = 1\n20 PRINT EXIST(\"A\"); EXIST(\"B\")\n", "");
+        assert!(result.is_ok());
+        assert_eq!(output, "truefalse\n");
+        assert_eq!(error_kind("10 PRINT EXIST(1)\n", ""), ErrorKind::TypeMismatch);
+    }
+
+    #[test]
+    fn eval_prints_an_expression_from_a_string() {
+        let (result, output) = run_with_input("10 LET A = 4\n20 EVAL \"A * (2 + 1)\"\n", "");
+        assert!(result.is_ok());
+        assert_eq!(output, "12\n");
+
+        let error = run_with_input("10 EVAL \"1 @ 2\"\n", "").0.unwrap_err();
+        assert_eq!(error.kind, ErrorKind::Syntax);
+        assert!(error.message.starts_with("EVAL of \"1 @ 2\" failed"), "{}", error.message);
+        assert_eq!(error_kind("10 EVAL 5\n", ""), ErrorKind::Syntax);
+    }
+
+    #[test]
+    fn mid_assignment_overwrites_in_place() {
+        let source = "10 LET A$ = \"abcdef\"\n20 MID$(A$, 2) = \"XY\"\n30 PRINT A$\n40 MID$(A$, 5, 1) = \"ZZZ\"\n50 PRINT A$\n60 MID$(A$, 6) = \"long\"\n70 PRINT A$\n";
+        let (result, output) = run_with_input(source, "");
+        assert!(result.is_ok());
+        assert_eq!(output, "aXYdef\naXYdZf\naXYdZl\n");
+
+        let error = run_with_input("10 LET A$ = \"abc\"\n20 MID$(A$, 4) = \"X\"\n", "").0.unwrap_err();
+        assert_eq!(error.kind, ErrorKind::OutOfRange);
+        assert_eq!(error.message, "MID$ start 4 out of range for a string of length 3");
+    }
+
+    #[test]
+    fn environ_reads_the_injected_environment() {
+        let code_lines = lexer::tokenize_program("10 PRINT ENVIRON$(\"HOME\"); \"|\"; ENVIRON$(\"UNSET\"); \"|\"\n").unwrap();
+        let environment = [("HOME".to_string(), "/home/basic".to_string())].iter().cloned().collect();
+        let mut interpreter = Interpreter::builder().environment(environment).build();
+        let mut output = Vec::new();
+        assert!(interpreter.run_with_io(&code_lines, &mut Cursor::new(""), &mut output).is_ok());
+        assert_eq!(String::from_utf8(output).unwrap(), "/home/basic||\n");
+
+        assert_eq!(error_kind("10 PRINT ENVIRON$(1)\n", ""), ErrorKind::TypeMismatch);
+    }
+
+    #[test]
+    fn precision_statement_rounds_what_print_shows() {
+        let source = "10 PRECISION 3\n20 PRINT 2 / 3; \" \"; STR$(1234.5)\n30 PRECISION 0\n40 PRINT 2 / 3\n";
+        let (result, output) = run_with_input(source, "");
+        assert!(result.is_ok());
+        assert_eq!(output, "0.667 1.23E+03\n0.666666666666667\n");
+
+        let error = run_with_input("10 PRECISION 18\n", "").0.unwrap_err();
+        assert_eq!(error.message, "PRECISION must be followed by a number of digits from 0 to 17");
+    }
+
+    #[test]
+    fn seeded_rnd_gives_a_known_sequence() {
+        let context = Context::new();
+        context.rng_state.set(seed_state(42));
+        let numbers: Vec<f64> = (0..3).map(|_| context.next_random()).collect();
+        assert_eq!(numbers, [0.03202137328092969, 0.029273180728959458, 0.04806575535995594]);
+
+        // As PRINT shows them, rounded to the default 15 digits
+        assert!(random_numbers(42).starts_with("0.0320213732809297\n0.0292731807289595\n0.0480657553599559\n"));
+    }
+
+    #[test]
+    fn dim_initial_values_must_fill_the_array() {
+        let (result, output) = run_with_input("10 DIM A(2) = {1, 2, 3}\n20 PRINT A(0); A(2)\n", "");
+        assert!(result.is_ok());
+        assert_eq!(output, "13\n");
+
+        for source in ["10 DIM A(2) = {1, 2}\n", "10 DIM A(2) = {1, 2, 3, 4}\n"] {
+            let error = run_with_input(source, "").0.unwrap_err();
+            assert_eq!(error.kind, ErrorKind::Syntax);
+            assert!(error.message.starts_with("DIM A(2) expects 3 initial values, got"), "{}", error.message);
+        }
+    }
+
+    #[test]
+    fn coverage_counts_every_line() {
+        let code_lines = lexer::tokenize_program("10 FOR I = 1 TO 3\n20 NEXT I\n30 IF I > 9 THEN PRINT \"never\"\n40 END\n50 PRINT \"dead\"\n").unwrap();
+        let mut interpreter = Interpreter::builder().coverage(true).build();
+        interpreter.run_with_io(&code_lines, &mut Cursor::new(""), &mut Vec::new()).unwrap();
+
+        let counts: Vec<(u32, usize)> = interpreter.coverage().unwrap().iter().map(|(line, count)| (line.0, *count)).collect();
+        assert_eq!(counts, [(10, 1), (20, 3), (30, 1), (40, 1), (50, 0)]);
+        assert!(Interpreter::new().coverage().is_none());
+    }
+
+    #[test]
+    fn trace_sees_each_statement_in_order() {
+        let code_lines = lexer::tokenize_program("10 LET A = 1\n20 GOTO 40\n30 LET A = 2\n40 PRINT A\n").unwrap();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let log = Arc::clone(&seen);
+        let mut interpreter = Interpreter::builder()
+            .trace(move |line, context| log.lock().unwrap().push((line.0, context.variable("A").map(|a| a.to_string()))))
+            .build();
+        interpreter.run_with_io(&code_lines, &mut Cursor::new(""), &mut Vec::new()).unwrap();
+
+        let one = Some("1".to_string());
+        assert_eq!(*seen.lock().unwrap(), [(10, None), (20, one.clone()), (40, one)]);
+    }
+
+    #[test]
+    fn program_draws_into_the_screen() {
+        let code_lines = lexer::tokenize_program("10 SCREEN 3, 3\n20 PSET (1, 1)\n30 LINE (0, 2)-(2, 2)\n").unwrap();
+        let mut interpreter = Interpreter::new();
+        interpreter.run_with_io(&code_lines, &mut Cursor::new(""), &mut Vec::new()).unwrap();
+        assert_eq!(interpreter.screen().unwrap().to_text(), "...\n.#.\n###\n");
+    }
+
+    #[test]
+    fn cls_clears_the_terminal_and_resets_the_column() {
+        let (result, output) = run_with_input("10 PRINT \"ab\";\n20 CLS\n30 PRINT \"x\", \"y\"\n", "");
+        assert!(result.is_ok());
+        assert_eq!(output, format!("ab{}x{}y\n", CLEAR_SCREEN, " ".repeat(PRINT_ZONE_WIDTH - 1)));
+        assert_eq!(CLEAR_SCREEN, "\x1b[2J\x1b[H");
+    }
+
+    #[test]
+    fn fractional_step_reaches_the_endpoint() {
+        let (result, output) = run_with_input("10 FOR X = 0 TO 1 STEP 0.25\n20 PRINT X; \" \";\n30 NEXT X\n40 PRINT\n", "");
+        assert!(result.is_ok());
+        assert_eq!(output, "0 0.25 0.5 0.75 1 \n");
+
+        let (result, output) = run_with_input("10 FOR X = 1 TO 0 STEP -0.5\n20 PRINT X; \" \";\n30 NEXT X\n40 PRINT\n", "");
+        assert!(result.is_ok());
+        assert_eq!(output, "1 0.5 0 \n");
+    }
+
+    #[test]
+    fn commas_pad_to_print_zones() {
+        let source = "10 PRINT 1, \"two\", 3\n20 PRINT \"fourteen chars\", \"x\"\n30 PRINT \"a\",\n40 PRINT \"b\"\n";
+        let (result, output) = run_with_input(source, "");
+        assert!(result.is_ok());
+        let zone = |text: &str| format!("{:1$}", text, PRINT_ZONE_WIDTH);
+        assert_eq!(
+            output,
+            // An item that fills its zone exactly still moves on to the next one
+            format!("{}{}3\n{}{}x\n{}b\n", zone("1"), zone("two"), zone("fourteen chars"), zone(""), zone("a"))
+        );
+    }
+
+    #[test]
+    fn for_end_and_step_are_evaluated_once() {
+        let source = "10 LET N = 3\n20 LET S = 1\n30 FOR I = 1 TO N STEP S\n40 LET N = 100\n50 LET S = 10\n60 PRINT I;\n70 NEXT I\n80 PRINT\n";
+        let (result, output) = run_with_input(source, "");
+        assert!(result.is_ok());
+        assert_eq!(output, "123\n");
+    }
+
+    #[test]
+    fn printed_comparison_matches_stored_boolean() {
+        let source = "10 PRINT 1 < 2; 2 < 1\n20 LET B = 1 < 2\n30 LET C = 2 < 1\n40 PRINT B; C\n";
+        let code_lines = lexer::tokenize_program(source).unwrap();
+        for &(mode, expected) in &[(value::BoolMode::Words, "truefalse\n"), (value::BoolMode::Numeric, "-10\n")] {
+            let mut interpreter = Interpreter::builder().bool_mode(mode).build();
+            let mut output = Vec::new();
+            assert!(interpreter.run_with_io(&code_lines, &mut Cursor::new(""), &mut output).is_ok());
+            assert_eq!(String::from_utf8(output).unwrap(), expected.repeat(2));
+        }
+    }
+
+
+    #[test]
+    fn data_ignores_a_trailing_comment() {
+        let source = "10 DATA 1, 2, 3 ' counts\n20 DATA 4 REM more\n30 FOR I = 1 TO 4\n40 READ A\n50 PRINT A;\n60 NEXT I\n70 READ A\n";
+        let (result, output) = run_with_input(source, "");
+        assert_eq!(output, "1234");
+        assert_eq!(result.unwrap_err().kind, ErrorKind::OutOfData);
+    }
+
+
+    #[test]
+    fn nested_while_loops_pair_with_their_own_wend() {
+        let source = "10 LET I = 0\n20 WHILE I < 3\n30 LET J = 0\n40 WHILE J < I\n50 PRINT I; J; \" \";\n60 LET J = J + 1\n70 WEND\n80 LET I = I + 1\n90 WEND\n100 PRINT \"done\"\n";
+        let (result, output) = run_with_input(source, "");
+        assert!(result.is_ok());
+        // The inner loop starts false on the first pass and is skipped
+        assert_eq!(output, "10 20 21 done\n");
+    }
+
+
+    #[test]
+    fn bare_next_closes_the_innermost_for() {
+        let source = "10 FOR I = 1 TO 2\n20 FOR J = 1 TO 2\n30 PRINT I; J; \" \";\n40 NEXT\n50 NEXT I\n60 PRINT\n";
+        let (result, output) = run_with_input(source, "");
+        assert!(result.is_ok());
+        assert_eq!(output, "11 12 21 22 \n");
+
+        let error = run_with_input("10 NEXT\n", "").0.unwrap_err();
+        assert_eq!(error.kind, ErrorKind::NextWithoutFor);
+        assert_eq!(error.message, "NEXT without matching FOR");
+    }
+
+
+    #[test]
+    fn goto_and_gosub_reach_labels() {
+        let source = "10 GOSUB greet\n20 GOTO finish\n30 PRINT \"skipped\"\n100 greet: PRINT \"hi\"\n110 RETURN\n200 finish: PRINT \"done\"\n";
+        let (result, output) = run_with_input(source, "");
+        assert!(result.is_ok());
+        assert_eq!(output, "hi\ndone\n");
+
+        let error = run_with_input("10 GOTO nowhere\n", "").0.unwrap_err();
+        assert_eq!(error.kind, ErrorKind::BadGoto);
+        assert_eq!(error.message, "GOTO to undefined label nowhere");
+
+        let error = run_with_input("10 here: PRINT 1\n20 here: PRINT 2\n", "").0.unwrap_err();
+        assert_eq!(error.message, "Label here is already defined on line 10");
+    }
+
+
+    #[test]
+    fn print_error_names_the_failing_expression() {
+        let error = run_with_input("10 PRINT 1 + Z\n", "").0.unwrap_err();
+        assert_eq!(error.kind, ErrorKind::UndefinedVariable);
+        assert_eq!(error.message, "Error in PRINT expression: Invalid variable reference Z in expression");
+    }
+
+
+    #[test]
+    fn while_goto_and_for_loops_count_alike() {
+        // The loop shapes benches/loops.rs times, checked for their iteration counts
+        let programs = [
+            "10 LET I = 0\n20 WHILE I < 1000\n30 LET I = I + 1\n40 WEND\n50 PRINT I\n",
+            "10 LET I = 0\n20 LET I = I + 1\n30 IF I = 1000 THEN 50\n40 GOTO 20\n50 PRINT I\n",
+            "10 LET N = 0\n20 FOR I = 1 TO 1000\n30 LET N = N + 1\n40 NEXT I\n50 PRINT N\n",
+        ];
+        for source in programs.iter() {
+            let (result, output) = run_with_input(source, "");
+            assert!(result.is_ok());
+            assert_eq!(output, "1000\n");
+        }
+    }
+
+
+    #[test]
+    fn input_chars_reads_from_injected_reader() {
+        let (result, output) = run_with_input("10 LET A$ = INPUT$(3)\n20 PRINT A$\n", "abcdef");
+        assert!(result.is_ok());
+        assert_eq!(output, "abc\n");
+    }
+
+    #[test]
+    fn input_chars_stops_short_at_end_of_input() {
+        let (result, output) = run_with_input("10 PRINT LEN(INPUT$(5))\n", "ab");
+        assert!(result.is_ok());
+        assert_eq!(output, "2\n");
+    }
+
+    #[test]
+    fn input_chars_shares_reader_with_input() {
+        let (result, output) = run_with_input("10 LET A$ = INPUT$(2)\n20 INPUT B$\n30 PRINT A$; B$\n", "xyrest\n");
+        assert!(result.is_ok());
+        assert_eq!(output, "xyrest\n");
     }
 }
@@ -2,26 +2,66 @@ use crate::token;
 
 use itertools::Itertools;
 
-use std::str::FromStr;
+use std::{
+    collections::{BTreeMap, HashSet},
+    convert::TryFrom,
+    fmt,
+    str::FromStr,
+};
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct LineNumber(pub u32);
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct TokenAndPos(pub u32, pub token::Token);
 
+// Whether source lines start with their line number, or are unnumbered and
+// run in the order they appear in the file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Numbering {
+    Numbered,
+    Sequential,
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct LineOfCode {
     pub line_number: LineNumber,
     pub tokens: Vec<TokenAndPos>,
 }
 
-pub fn tokenize_line(line: &str) -> Result<LineOfCode, String> {
+// Why a line couldn't be lexed, `pos` being the character it went wrong at
+#[derive(Debug, Clone, PartialEq)]
+pub struct LexError {
+    pub pos: u32,
+    pub message: String,
+}
+
+impl fmt::Display for LexError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at column {}", self.message, self.pos)
+    }
+}
+
+// A LexError in a whole program, `line` being the 0-based index of the
+// offending source line
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProgramLexError {
+    pub line: usize,
+    pub error: LexError,
+}
+
+impl fmt::Display for ProgramLexError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} on source line {}", self.error, self.line)
+    }
+}
+
+pub fn tokenize_line(line: &str) -> Result<LineOfCode, LexError> {
     let mut char_iter = line.chars().enumerate().peekable();
     let mut line_number = LineNumber(0);
     let mut tokens: Vec<TokenAndPos> = Vec::new();
 
-    while char_iter.peek() != None {
+    while char_iter.peek().is_some() {
         let (pos, ch) = char_iter.next().unwrap();
         let pos = pos as u32;
 
@@ -37,12 +77,17 @@ pub fn tokenize_line(line: &str) -> Result<LineOfCode, String> {
 
                 match u32::from_str(num_str.as_str()) {
                     Ok(number) => line_number = LineNumber(number),
+                    Err(_) if num_str.chars().all(|x| x.is_ascii_digit()) => {
+                        return Err(LexError {
+                            pos: 0,
+                            message: format!("Line number out of range, the largest is {}", u32::MAX),
+                        })
+                    }
                     Err(_) => {
-                        return Err(format!(
-                            "Line must start with number followed by \
-                                            whitespace:\n\t{}",
-                            line
-                        ))
+                        return Err(LexError {
+                            pos: 0,
+                            message: "Line must start with number followed by whitespace".to_string(),
+                        })
                     }
                 };
             }
@@ -53,7 +98,10 @@ pub fn tokenize_line(line: &str) -> Result<LineOfCode, String> {
                 });
             }
             else {
-                return Err(format!("Line must start with a line number:\n\t{}", line));
+                return Err(LexError {
+                    pos: 0,
+                    message: "Line must start with a line number".to_string(),
+                });
             }
         } else {
             match ch {
@@ -65,88 +113,189 @@ pub fn tokenize_line(line: &str) -> Result<LineOfCode, String> {
                 // At the beginning of a string
                 '"' => {
                     // TODO: Handle escaped quotes
-                    // TODO: Handle malformed string
-                    let str_chars: Vec<char> = char_iter
-                        .by_ref()
-                        .take_while(|&(_, x)| x != '"')
-                        .map(|(_, x)| x)
-                        .collect();
-                    let bstring: String = str_chars.into_iter().collect();
+                    let mut bstring = String::new();
+                    loop {
+                        match char_iter.next() {
+                            Some((_, '"')) => break,
+                            Some((_, x)) => bstring.push(x),
+                            None => {
+                                return Err(LexError {
+                                    pos,
+                                    message: "Unterminated string, no closing \" before the end of the line".to_string(),
+                                })
+                            }
+                        }
+                    }
                     tokens.push(TokenAndPos(pos, token::Token::BString(bstring)))
                 }
                 '-' => {
-                    if !tokens.is_empty() && tokens.last().unwrap().1.is_value() {
-                        tokens.push(TokenAndPos(pos, token::Token::Minus))
-                    } else {
-                        tokens.push(TokenAndPos(pos, token::Token::UMinus))
+                    match tokens.last() {
+                        Some(TokenAndPos(_, ref last))
+                            if last.is_value() || *last == token::Token::RParen =>
+                        {
+                            tokens.push(TokenAndPos(pos, token::Token::Minus))
+                        }
+                        _ => tokens.push(TokenAndPos(pos, token::Token::UMinus)),
                     }
                 }
+                // &HFF and &B1010 where a value is expected, elsewhere & concatenates
+                '&' if matches!(char_iter.peek(), Some(&(_, 'H' | 'h' | 'B' | 'b')))
+                    && !matches!(tokens.last(), Some(TokenAndPos(_, ref last)) if last.is_value() || *last == token::Token::RParen) =>
+                {
+                    let (_, prefix) = char_iter.next().unwrap();
+                    let digits: String = char_iter
+                        .by_ref()
+                        .peeking_take_while(|&(_, x)| x.is_alphanumeric())
+                        .map(|(_, x)| x)
+                        .collect();
+                    let number = parse_radix_literal(prefix, &digits, pos)?;
+                    tokens.push(TokenAndPos(pos, token::Token::Number(number)))
+                }
+                // _5 would otherwise be an unknown token
+                '_' if matches!(char_iter.peek(), Some(&(_, x)) if x.is_ascii_digit()) => {
+                    return Err(LexError {
+                        pos,
+                        message: "Digit separator must be between digits".to_string(),
+                    })
+                }
+                // ' is short for REM
+                '\'' => {
+                    tokens.push(TokenAndPos(pos, token::Token::Rem));
+                    let comment_str: String = char_iter.by_ref().map(|(_, x)| x).collect();
+                    tokens.push(TokenAndPos(pos + 1, token::Token::Comment(comment_str.trim_start().to_string())))
+                }
                 '!' => tokens.push(TokenAndPos(pos, token::Token::Bang)),
                 '(' => tokens.push(TokenAndPos(pos, token::Token::LParen)),
                 ')' => tokens.push(TokenAndPos(pos, token::Token::RParen)),
-                _ => {
-                    // Otherwise, next token is until next whitespace or closing paren
+                ch if ch.is_ascii_digit() || ch == '.' => {
+                    // Numbers run until the next character that can't be part of one,
+                    // letters included so that malformed literals like 12AB are caught here
+                    let mut num_chars: Vec<char> = vec![ch];
+                    while let Some(&(_, x)) = char_iter.peek() {
+                        let exponent_sign = (x == '+' || x == '-')
+                            && matches!(num_chars.last(), Some('e') | Some('E'));
+
+                        if x.is_alphanumeric() || x == '.' || x == '_' || exponent_sign {
+                            num_chars.push(x);
+                            char_iter.next();
+                        } else {
+                            break;
+                        }
+                    }
+                    let mut token_str: String = num_chars.into_iter().collect();
+
+                    if token_str.contains('_') {
+                        token_str = match strip_digit_separators(&token_str) {
+                            Ok(stripped) => stripped,
+                            Err(index) => {
+                                return Err(LexError {
+                                    pos: pos + index as u32,
+                                    message: format!("Digit separator must be between digits in {}", token_str),
+                                })
+                            }
+                        };
+                    }
+
+                    match f64::from_str(token_str.as_str()) {
+                        Ok(number) => tokens.push(TokenAndPos(pos, token::Token::Number(number))),
+                        // 1e and 2E- are numbers with the exponent's digits missing
+                        Err(_) if is_missing_exponent(&token_str) => {
+                            return Err(LexError {
+                                pos,
+                                message: format!("Exponent has no digits in {}", token_str),
+                            })
+                        }
+                        Err(_) => {
+                            return Err(LexError {
+                                pos,
+                                message: format!("Invalid number {}", token_str),
+                            })
+                        }
+                    }
+                }
+                ch if ch.is_alphabetic() => {
+                    // Keywords and identifiers, where a $ can only come last: A$, LEFT$
                     let mut token_chars: Vec<char> = char_iter
                         .by_ref()
-                        .peeking_take_while(|&(_, x)| !(x.is_whitespace() || x == ')'))
+                        .peeking_take_while(|&(_, x)| x.is_alphanumeric() || x == '_')
                         .map(|(_, x)| x)
                         .collect();
                     token_chars.insert(0, ch);
+                    if let Some((_, sigil)) = char_iter.next_if(|&(_, x)| x == '$') {
+                        token_chars.push(sigil);
+                    }
                     let token_str: String = token_chars.into_iter().collect();
 
-                    if f64::from_str(token_str.as_str()).is_ok() {
-                        tokens.push(TokenAndPos(
-                            pos,
-                            token::Token::Number(f64::from_str(token_str.as_str()).unwrap()),
-                        ));
-                    } else {
-                        let token = token::Token::token_for_string(token_str.to_uppercase().as_str());
+                    let token = token::Token::token_for_string(token_str.to_uppercase().as_str());
 
-                        match token {
-                            None => {
-                                if is_valid_identifier(&token_str) {
-                                    let last = match tokens.last() {
-                                        Some(t) => t,
-                                        None => return Err(format!(
-                                            "Could not get last token at subcheck {}", pos
-                                        ))
-                                    };
-                                    
-                                    if last.1 == token::Token::Sub || last.1 == token::Token::Gosub {
-                                        tokens.push(TokenAndPos(
-                                            pos,
-                                            token::Token::Srout(token_str.to_string())
-                                        ));
-                                    } else {
-                                        tokens.push(TokenAndPos(
-                                            pos,
-                                            token::Token::Variable(token_str.to_string()),
-                                        ));
-                                    }
+                    match token {
+                        None => {
+                            if is_valid_identifier(&token_str) {
+                                if tokens.is_empty() && !token_str.ends_with('$') && matches!(char_iter.peek(), Some(&(_, ':'))) {
+                                    char_iter.next();
+                                    tokens.push(TokenAndPos(
+                                        pos,
+                                        token::Token::Label(token_str.to_string())
+                                    ));
+                                } else if matches!(
+                                    tokens.last(),
+                                    Some(TokenAndPos(_, token::Token::Sub | token::Token::Gosub))
+                                ) {
+                                    tokens.push(TokenAndPos(
+                                        pos,
+                                        token::Token::Srout(token_str.to_string())
+                                    ));
                                 } else {
-                                    return Err(format!(
-                                        "Unimplemented token at {}:\t{}",
-                                        pos, token_str
+                                    tokens.push(TokenAndPos(
+                                        pos,
+                                        token::Token::Variable(token_str.to_string()),
                                     ));
                                 }
+                            } else {
+                                return Err(LexError {
+                                    pos,
+                                    message: format!("Unimplemented token {}", token_str),
+                                });
                             }
+                        }
 
-                            Some(token::Token::Rem) => {
-                                tokens.push(TokenAndPos(pos, token::Token::Rem));
-                                // Skip the space after REM
-                                char_iter.next();
-                                // The rest of the line is a comment
-                                let comment_str: String =
-                                    char_iter.by_ref().map(|(_, x)| x).collect();
-                                tokens.push(TokenAndPos(
-                                    (pos + 4) as u32,
-                                    token::Token::Comment(comment_str),
-                                ))
-                            }
+                        Some(token::Token::Rem) => {
+                            tokens.push(TokenAndPos(pos, token::Token::Rem));
+                            // Skip the space after REM
+                            char_iter.next();
+                            // The rest of the line is a comment
+                            let comment_str: String =
+                                char_iter.by_ref().map(|(_, x)| x).collect();
+                            tokens.push(TokenAndPos(
+                                pos + 4,
+                                token::Token::Comment(comment_str),
+                            ))
+                        }
 
-                            Some(token) => {
-                                tokens.push(TokenAndPos(pos, token));
-                            }
+                        Some(token) => {
+                            tokens.push(TokenAndPos(pos, token));
+                        }
+                    }
+                }
+                _ => {
+                    // Operators and separators, preferring two-character operators like <=
+                    let mut token_str = ch.to_string();
+                    if let Some(&(_, next)) = char_iter.peek() {
+                        let pair = format!("{}{}", ch, next);
+                        if token::Token::token_for_string(pair.as_str()).is_some() {
+                            token_str = pair;
+                            char_iter.next();
+                        }
+                    }
+
+                    // Only pairs that are operators are taken, so an unknown token is the one character
+                    match token::Token::token_for_string(token_str.as_str()) {
+                        Some(token) => tokens.push(TokenAndPos(pos, token)),
+                        None => {
+                            return Err(LexError {
+                                pos,
+                                message: format!("Unexpected character '{}'", ch),
+                            })
                         }
                     }
                 }
@@ -160,17 +309,279 @@ pub fn tokenize_line(line: &str) -> Result<LineOfCode, String> {
     })
 }
 
+// Source text for a lexed line in a canonical layout, e.g. `10 PRINT A(1), "X"`.
+// Lexing the result gives back the same tokens
+pub fn format_line(line: &LineOfCode) -> String {
+    let mut text = line.line_number.0.to_string();
+    let mut previous: Option<&token::Token> = None;
+
+    for TokenAndPos(_, token) in line.tokens.iter() {
+        let spaced = match (previous, token) {
+            (Some(token::Token::LParen | token::Token::LBrace | token::Token::UMinus), _) => false,
+            (_, token::Token::RParen | token::Token::RBrace | token::Token::Comma | token::Token::Semicolon) => false,
+            // Calls and subscripts
+            (
+                Some(token::Token::Function(_) | token::Token::Variable(_) | token::Token::Tab | token::Token::Spc),
+                token::Token::LParen,
+            ) => false,
+            // The lexer drops the first character after REM
+            (Some(token::Token::Rem), token::Token::Comment(comment)) if comment.is_empty() => false,
+            _ => true,
+        };
+
+        if spaced {
+            text.push(' ');
+        }
+        text.push_str(&token.to_string());
+        previous = Some(token);
+    }
+
+    text
+}
+
+/// Lexes a whole program, leaving out blank lines and `#` comments. Errors carry
+/// the 0-based index of the offending source line.
+///
+/// ```
+/// use yarxbi::lexer::{tokenize_program, LineNumber, TokenAndPos};
+/// use yarxbi::token::Token;
+///
+/// let lines = tokenize_program("10 PRINT \"HI\"\n\n20 GOTO 10\n").unwrap();
+/// assert_eq!(lines.len(), 2);
+/// assert_eq!(lines[1].line_number, LineNumber(20));
+/// assert!(matches!(lines[1].tokens[..], [TokenAndPos(_, Token::Goto), TokenAndPos(_, Token::Number(_))]));
+///
+/// let error = tokenize_program("10 PRINT 1\nPRINT 2\n").unwrap_err();
+/// assert_eq!(error.line, 1);
+/// ```
+pub fn tokenize_program(source: &str) -> Result<Vec<LineOfCode>, ProgramLexError> {
+    tokenize_program_with(source, Numbering::Numbered)
+}
+
+pub fn tokenize_program_with(source: &str, numbering: Numbering) -> Result<Vec<LineOfCode>, ProgramLexError> {
+    tokenize_lines(source, numbering).map(|line| line.map(|(_, code)| code)).collect()
+}
+
+// Lexes a program a line at a time, leaving out blank lines and `#` comments.
+// Each item is the 0-based index of a source line with its code, so callers
+// that want every error, not just the first, can keep going past one
+pub fn tokenize_lines(
+    source: &str,
+    numbering: Numbering,
+) -> impl Iterator<Item = Result<(usize, LineOfCode), ProgramLexError>> + '_ {
+    source
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty() && !line.starts_with('#'))
+        .map(move |(lineno, line)| {
+            let code = match numbering {
+                Numbering::Numbered => tokenize_line(line),
+                Numbering::Sequential => {
+                    sequential_line_number(lineno).and_then(|line_number| tokenize_unnumbered_line(line, line_number))
+                }
+            };
+            code.map(|code| (lineno, code)).map_err(|error| ProgramLexError { line: lineno, error })
+        })
+}
+
+// Lexes a line of a Sequential program, which has no line number of its own
+// and is given `line_number`
+pub fn tokenize_unnumbered_line(line: &str, line_number: LineNumber) -> Result<LineOfCode, LexError> {
+    Ok(LineOfCode {
+        line_number,
+        tokens: tokenize_expression(line)?,
+    })
+}
+
+// Number given to the line at 0-based `index` of a Sequential program's
+// source: 10, 20, 30 and so on, so errors still point at the source line
+pub fn sequential_line_number(index: usize) -> Result<LineNumber, LexError> {
+    u32::try_from(index + 1)
+        .ok()
+        .and_then(|line| line.checked_mul(10))
+        .map(LineNumber)
+        .ok_or(LexError {
+            pos: 0,
+            message: format!("Too many lines to number, line {} is past the limit", index + 1),
+        })
+}
+
+// Lexes a bare expression, as given to EVAL, with positions relative to its start
+pub fn tokenize_expression(source: &str) -> Result<Vec<TokenAndPos>, LexError> {
+    let line = tokenize_line(format!("0 {}", source).as_str()).map_err(|e| LexError {
+        pos: e.pos.saturating_sub(2),
+        message: e.message,
+    })?;
+
+    Ok(line
+        .tokens
+        .into_iter()
+        .map(|TokenAndPos(pos, token)| TokenAndPos(pos - 2, token))
+        .collect())
+}
+
+// Applies #DEFINE, #IFDEF, #IFNDEF, #ELSE and #ENDIF before lexing. Lines that
+// are left out and the directives themselves become `#` comments, so the result
+// has as many lines as the source and positions in errors stay valid.
+// Errors carry the 0-based index of the offending line
+pub fn preprocess(source: &str, defines: &HashSet<String>) -> Result<String, (usize, String)> {
+    let mut defines = defines.clone();
+    // One entry per open #IFDEF: whether its current branch is kept, and where it started
+    let mut conditions: Vec<(bool, usize)> = Vec::new();
+    let mut lines = Vec::new();
+
+    for (lineno, line) in source.lines().enumerate() {
+        let including = conditions.iter().all(|&(keep, _)| keep);
+        let trimmed = line.trim();
+
+        if !trimmed.starts_with('#') {
+            lines.push(if including { line } else { "#" });
+            continue;
+        }
+
+        let mut words = trimmed[1..].split_whitespace();
+        let directive = words.next().unwrap_or("").to_uppercase();
+        let name = words.next();
+
+        match (directive.as_str(), name) {
+            ("DEFINE", Some(name)) if including => {
+                defines.insert(name.to_string());
+            }
+            ("IFDEF", Some(name)) => conditions.push((defines.contains(name), lineno)),
+            ("IFNDEF", Some(name)) => conditions.push((!defines.contains(name), lineno)),
+            ("DEFINE", None) | ("IFDEF", None) | ("IFNDEF", None) => {
+                return Err((lineno, format!("#{} must be followed by a name", directive)))
+            }
+            ("ELSE", _) => match conditions.last_mut() {
+                Some((keep, _)) => *keep = !*keep,
+                None => return Err((lineno, "#ELSE without #IFDEF".to_string())),
+            },
+            ("ENDIF", _) => {
+                conditions
+                    .pop()
+                    .ok_or((lineno, "#ENDIF without #IFDEF".to_string()))?;
+            }
+            // Anything else is an ordinary comment
+            _ => {}
+        }
+
+        lines.push("#");
+    }
+
+    if let Some(&(_, start)) = conditions.last() {
+        return Err((start, "#IFDEF is never closed by #ENDIF".to_string()));
+    }
+
+    Ok(lines.join("\n"))
+}
+
+// Yields the lines of a lexed program in execution order, i.e. sorted by line
+// number; a repeated line number, which running rejects, keeps its last occurrence
+pub fn lines_in_order(
+    code_lines: &[LineOfCode],
+) -> impl Iterator<Item = (LineNumber, &[TokenAndPos])> {
+    code_lines
+        .iter()
+        .map(|line| (line.line_number, line.tokens.as_slice()))
+        .collect::<BTreeMap<_, _>>()
+        .into_iter()
+}
+
+// Remembers which line number each renumbered line had before, so errors
+// reported against the new numbers can still name the line the user wrote
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LineMapping {
+    new_to_old: BTreeMap<LineNumber, LineNumber>,
+}
+
+impl LineMapping {
+    pub fn original(&self, line_number: LineNumber) -> Option<LineNumber> {
+        self.new_to_old.get(&line_number).copied()
+    }
+
+    // "100 (was 35)", or just "100" for lines the mapping doesn't know
+    pub fn describe(&self, line_number: LineNumber) -> String {
+        match self.original(line_number) {
+            Some(old) if old != line_number => format!("{} (was {})", line_number.0, old.0),
+            _ => line_number.0.to_string(),
+        }
+    }
+}
+
+// Numbers the lines start, start + step, ... in execution order and rewrites
+// literal jump targets after GOTO, GOSUB, THEN, ELSE, RESTORE, RESUME and in ON lists.
+// Computed targets like GOTO 10 + X are left alone
+pub fn renumber(
+    code_lines: &[LineOfCode],
+    start: u32,
+    step: u32,
+) -> Result<(Vec<LineOfCode>, LineMapping), String> {
+    let mut old_to_new = BTreeMap::new();
+    let mut mapping = LineMapping::default();
+
+    for (index, (old, _)) in lines_in_order(code_lines).enumerate() {
+        let new = (index as u32)
+            .checked_mul(step)
+            .and_then(|offset| offset.checked_add(start))
+            .ok_or(format!("Renumbering line {} overflows the line number range", old.0))?;
+
+        old_to_new.insert(old, LineNumber(new));
+        mapping.new_to_old.insert(LineNumber(new), old);
+    }
+
+    let renumbered = lines_in_order(code_lines)
+        .map(|(old, tokens)| {
+            let mut tokens = tokens.to_vec();
+            let mut expecting_target = false;
+
+            for index in 0..tokens.len() {
+                let next_is_operator = matches!(tokens.get(index + 1), Some(TokenAndPos(_, next)) if next.is_operator());
+
+                match tokens[index].1 {
+                    token::Token::Number(number) if expecting_target && !next_is_operator => {
+                        if let Some(new) = old_to_new.get(&LineNumber(number as u32)) {
+                            // `as` saturates, so a target past u32::MAX could pass for u32::MAX
+                            if number.fract() == 0.0 && number <= u32::MAX as f64 {
+                                tokens[index].1 = token::Token::Number(new.0 as f64);
+                            }
+                        }
+                        continue;
+                    }
+                    // ON X GOSUB 10, 20, 30
+                    token::Token::Comma if expecting_target => continue,
+                    token::Token::Goto
+                    | token::Token::Gosub
+                    | token::Token::Then
+                    | token::Token::Else
+                    | token::Token::Restore
+                    | token::Token::Resume => {
+                        expecting_target = true;
+                        continue;
+                    }
+                    _ => {}
+                }
+
+                expecting_target = false;
+            }
+
+            LineOfCode {
+                line_number: old_to_new[&old],
+                tokens,
+            }
+        })
+        .collect();
+
+    Ok((renumbered, mapping))
+}
+
 // Starts with [a-zA-Z_]
 // Followed by any number of [a-zA-Z0-9_]
+// And optionally a $ for a string variable
 fn is_valid_identifier(token_str: &str) -> bool {
-    let mut v = token_str.chars();
-    let c = v.next();
-    match c {
-        Some(c) => match c {
-            'a'..='z' | 'A'..='Z' => (),
-            _ => return false,
-        },
-        None => return false,
+    let mut v = token_str.strip_suffix('$').unwrap_or(token_str).chars();
+    match v.next() {
+        Some('a'..='z') | Some('A'..='Z') => (),
+        _ => return false,
     }
     for c in v {
         match c {
@@ -180,3 +591,239 @@ fn is_valid_identifier(token_str: &str) -> bool {
     }
     true
 }
+
+// Largest &H or &B literal, beyond it an f64 can't hold every whole number exactly
+const MAX_RADIX_LITERAL: u64 = 1 << 53;
+
+// The digits of a &H (hexadecimal) or &B (binary) literal starting at `pos`. They are
+// read as unsigned, &HFFFF is 65535 rather than -1 as in 16-bit BASICs
+fn parse_radix_literal(prefix: char, digits: &str, pos: u32) -> Result<f64, LexError> {
+    let (radix, name) = match prefix.to_ascii_uppercase() {
+        'H' => (16, "hexadecimal"),
+        _ => (2, "binary"),
+    };
+
+    if digits.is_empty() {
+        return Err(LexError {
+            pos,
+            message: format!("&{} must be followed by {} digits", prefix, name),
+        });
+    }
+    if let Some((index, digit)) = digits.chars().enumerate().find(|(_, x)| !x.is_digit(radix)) {
+        return Err(LexError {
+            pos: pos + 2 + index as u32,
+            message: format!("Invalid {} digit {} in &{}{}", name, digit, prefix, digits),
+        });
+    }
+
+    match u64::from_str_radix(digits, radix) {
+        Ok(number) if number <= MAX_RADIX_LITERAL => Ok(number as f64),
+        _ => Err(LexError {
+            pos,
+            message: format!("Number too large &{}{}, the maximum is &H20000000000000", prefix, digits),
+        }),
+    }
+}
+
+fn is_missing_exponent(token_str: &str) -> bool {
+    match token_str.trim_end_matches(&['+', '-'][..]).strip_suffix(&['e', 'E'][..]) {
+        Some(mantissa) => f64::from_str(mantissa).is_ok(),
+        None => false,
+    }
+}
+
+// Numeric literals may separate digits with `_`, e.g. 1_000_000
+// Every underscore must sit between two digits, so `5_`, `5__0`, `1_.5` and `1_e3` are rejected
+// with the index of the misplaced underscore
+fn strip_digit_separators(token_str: &str) -> Result<String, usize> {
+    let chars: Vec<char> = token_str.chars().collect();
+
+    for (i, &c) in chars.iter().enumerate() {
+        if c != '_' {
+            continue;
+        }
+
+        let before = if i > 0 { chars.get(i - 1) } else { None };
+        let after = chars.get(i + 1);
+
+        match (before, after) {
+            (Some(b), Some(a)) if b.is_ascii_digit() && a.is_ascii_digit() => (),
+            _ => return Err(i),
+        }
+    }
+
+    Ok(chars.into_iter().filter(|&x| x != '_').collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Lexes `expression` as the argument of a PRINT
+    fn lex(expression: &str) -> Result<Vec<token::Token>, LexError> {
+        let line = tokenize_line(&format!("10 PRINT {}", expression))?;
+        Ok(line.tokens.into_iter().skip(1).map(|TokenAndPos(_, token)| token).collect())
+    }
+
+    // The error for `expression`, with its position counted from the start of the expression
+    fn lex_error(expression: &str) -> (u32, String) {
+        let error = lex(expression).expect_err("expression should not lex");
+        (error.pos - "10 PRINT ".len() as u32, error.message)
+    }
+
+    #[test]
+    fn digit_separators_between_digits() {
+        assert_eq!(lex("1_000").unwrap(), [token::Token::Number(1000.0)]);
+        for bad in ["1__0", "_1", "1_", "1_e3"] {
+            assert!(lex(bad).is_err(), "{} should not lex", bad);
+        }
+    }
+
+    #[test]
+    fn exponents_lex_as_numbers() {
+        assert_eq!(lex("1e3").unwrap(), [token::Token::Number(1000.0)]);
+        assert_eq!(lex("2E-4").unwrap(), [token::Token::Number(0.0002)]);
+        assert_eq!(lex("1.5e+2").unwrap(), [token::Token::Number(150.0)]);
+    }
+
+    #[test]
+    fn exponent_without_digits_is_an_error() {
+        assert_eq!(lex_error("1e"), (0, "Exponent has no digits in 1e".to_string()));
+        assert_eq!(lex_error("2 + 1e+"), (4, "Exponent has no digits in 1e+".to_string()));
+    }
+
+    #[test]
+    fn radix_literals() {
+        assert_eq!(lex("&HFF").unwrap(), [token::Token::Number(255.0)]);
+        assert_eq!(lex("&hff").unwrap(), [token::Token::Number(255.0)]);
+        assert_eq!(lex("&B101").unwrap(), [token::Token::Number(5.0)]);
+        assert_eq!(lex_error("&HFG"), (3, "Invalid hexadecimal digit G in &HFG".to_string()));
+        assert_eq!(lex_error("&B102"), (4, "Invalid binary digit 2 in &B102".to_string()));
+        assert_eq!(lex_error("&H"), (0, "&H must be followed by hexadecimal digits".to_string()));
+    }
+
+    #[test]
+    fn radix_literals_stop_at_two_to_the_53() {
+        assert_eq!(lex("&H20000000000000").unwrap(), [token::Token::Number(9_007_199_254_740_992.0)]);
+        assert_eq!(
+            lex_error("&H20000000000001"),
+            (0, "Number too large &H20000000000001, the maximum is &H20000000000000".to_string())
+        );
+    }
+
+    #[test]
+    fn misplaced_separator_is_pointed_at() {
+        let cases = [("1__0", 1), ("_1", 0), ("1_", 1), ("1_e3", 1), ("1_.5", 1), ("12_34_", 5)];
+        for (bad, column) in cases {
+            let (pos, message) = lex_error(bad);
+            assert_eq!(pos, column, "wrong column for {}", bad);
+            assert!(message.starts_with("Digit separator must be between digits"), "{}", message);
+        }
+    }
+
+    #[test]
+    fn errors_carry_their_column() {
+        assert_eq!(lex_error("1 + 12AB"), (4, "Invalid number 12AB".to_string()));
+        assert_eq!(tokenize_line("PRINT 1").unwrap_err().pos, 0);
+        assert_eq!(tokenize_expression("1 + 12AB").unwrap_err().pos, 4);
+    }
+
+    #[test]
+    fn unterminated_string_points_at_opening_quote() {
+        assert_eq!(
+            lex_error("1; \"abc"),
+            (3, "Unterminated string, no closing \" before the end of the line".to_string())
+        );
+    }
+
+    #[test]
+    fn unexpected_character_is_named_with_its_column() {
+        assert_eq!(lex_error("1 @ 2"), (2, "Unexpected character '@'".to_string()));
+        assert_eq!(lex_error("A ~"), (2, "Unexpected character '~'".to_string()));
+    }
+
+    #[test]
+    fn format_line_lexes_back_to_the_same_tokens() {
+        let sources = [
+            "10 REM a comment",
+            "10 REM",
+            "10 PRINT 1 ' trailing",
+            "10 start: PRINT -A * -(B + 1)",
+            "10 DEF FN SQ(X, Y) = X * X + Y",
+            "10 PRINT FN SQ(3, 1); TAB(5); SPC(2); A$(1, 2)",
+            "10 DIM A(2) = {1, -2, \"three\"}",
+            "10 IF A <= 2 THEN GOTO start ELSE PRINT \"no\",",
+        ];
+
+        for source in sources {
+            let line = tokenize_line(source).unwrap();
+            let formatted = format_line(&line);
+            let relexed = tokenize_line(&formatted).unwrap_or_else(|e| panic!("{} from {}: {}", formatted, source, e));
+            let tokens = |line: &LineOfCode| line.tokens.iter().map(|TokenAndPos(_, token)| token.clone()).collect::<Vec<_>>();
+            assert_eq!(relexed.line_number, line.line_number);
+            assert_eq!(tokens(&relexed), tokens(&line), "{} from {}", formatted, source);
+        }
+    }
+
+    fn defines(names: &[&str]) -> HashSet<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn preprocess_keeps_the_defined_branch() {
+        let source = "10 PRINT 1\n#IFDEF DEBUG\n20 PRINT 2\n#ELSE\n20 PRINT 3\n#ENDIF\n30 END";
+        assert_eq!(
+            preprocess(source, &defines(&["DEBUG"])).unwrap(),
+            "10 PRINT 1\n#\n20 PRINT 2\n#\n#\n#\n30 END"
+        );
+        assert_eq!(
+            preprocess(source, &defines(&[])).unwrap(),
+            "10 PRINT 1\n#\n#\n#\n20 PRINT 3\n#\n30 END"
+        );
+
+        let source = "#DEFINE FAST\n#IFNDEF FAST\n10 PRINT 1\n#ENDIF\n20 PRINT 2";
+        assert_eq!(preprocess(source, &defines(&[])).unwrap(), "#\n#\n#\n#\n20 PRINT 2");
+    }
+
+    #[test]
+    fn preprocess_rejects_unbalanced_directives() {
+        let none = defines(&[]);
+        assert_eq!(preprocess("10 PRINT 1\n#ENDIF", &none), Err((1, "#ENDIF without #IFDEF".to_string())));
+        assert_eq!(preprocess("#ELSE", &none), Err((0, "#ELSE without #IFDEF".to_string())));
+        assert_eq!(
+            preprocess("#IFDEF A\n#IFDEF B\n#ENDIF", &none),
+            Err((0, "#IFDEF is never closed by #ENDIF".to_string()))
+        );
+        assert_eq!(preprocess("#IFDEF", &none), Err((0, "#IFDEF must be followed by a name".to_string())));
+    }
+
+    #[test]
+    fn renumber_rewrites_targets_and_maps_back() {
+        let code_lines = tokenize_program("35 GOTO 5\n5 ON X GOSUB 35, 40\n40 GOTO 5 + X\n").unwrap();
+        let (renumbered, mapping) = renumber(&code_lines, 100, 10).unwrap();
+
+        let listing: Vec<String> = renumbered.iter().map(format_line).collect();
+        assert_eq!(listing, ["100 ON X GOSUB 110, 120", "110 GOTO 100", "120 GOTO 5 + X"]);
+
+        assert_eq!(mapping.original(LineNumber(110)), Some(LineNumber(35)));
+        assert_eq!(mapping.original(LineNumber(130)), None);
+        assert_eq!(mapping.describe(LineNumber(110)), "110 (was 35)");
+        assert_eq!(mapping.describe(LineNumber(999)), "999");
+    }
+
+    #[test]
+    fn renumber_past_the_line_number_range_fails() {
+        let code_lines = tokenize_program("10 PRINT 1\n20 PRINT 2\n").unwrap();
+        assert_eq!(
+            renumber(&code_lines, u32::MAX, 1),
+            Err("Renumbering line 20 overflows the line number range".to_string())
+        );
+    }
+
+    #[test]
+    fn lines_in_order_sorts_by_line_number() {
+        let code_lines = tokenize_program("30 END\n10 PRINT 1\n20 PRINT 2\n").unwrap();
+        let order: Vec<(u32, usize)> = lines_in_order(&code_lines).map(|(line, tokens)| (line.0, tokens.len())).collect();
+        assert_eq!(order, [(10, 2), (20, 2), (30, 1)]);
+    }
+}
@@ -1,4 +1,7 @@
+pub mod diagnostic;
+pub mod error;
 pub mod evaluator;
 pub mod lexer;
+pub mod screen;
 pub mod token;
 pub mod value;
\ No newline at end of file
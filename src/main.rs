@@ -1,6 +1,13 @@
-use std::{env, fs::File, io::Read, process::exit, time::Instant};
+use std::{
+    collections::{BTreeMap, HashSet},
+    env,
+    fs::File,
+    io::{self, IsTerminal, Read, Write},
+    process::exit,
+    time::{Duration, Instant},
+};
 
-use yarxbi::{lexer, evaluator};
+use yarxbi::{diagnostic, lexer, evaluator};
 
 fn read_file(path: &str) -> Result<String, std::io::Error> {
     let mut f = File::open(path)?;
@@ -9,37 +16,206 @@ fn read_file(path: &str) -> Result<String, std::io::Error> {
     Ok(s)
 }
 
+// Points at the offending character under the line
+fn report_lex_error(line: &str, lineno: usize, e: &lexer::LexError) {
+    eprintln!("Error at line {}, column {}: {}", lineno, e.pos, e.message);
+    eprintln!("\t{}", line);
+    eprintln!("\t{}^", " ".repeat(e.pos as usize));
+}
+
+// Interactive mode: a numbered line is added to the program, replacing any line with
+// the same number, or deletes that line when nothing follows the number. RUN, CONT,
+// LIST and NEW work on the program, anything else runs straight away against the same
+// variables, leaving a STOPped program ready to CONT. Ends at the end of input (Ctrl-D)
+fn repl(new_interpreter: &dyn Fn() -> evaluator::Interpreter) {
+    let interactive = io::stdin().is_terminal();
+    let mut interpreter = new_interpreter();
+    let mut program: BTreeMap<lexer::LineNumber, lexer::LineOfCode> = BTreeMap::new();
+
+    loop {
+        if interactive {
+            print!("> ");
+            let _ = io::stdout().flush();
+        }
+
+        // Stdin is only locked while reading a line, so INPUT can use it as well
+        let mut line = String::new();
+        match io::stdin().read_line(&mut line) {
+            Ok(0) => break,
+            Ok(_) => {}
+            Err(err) => {
+                eprintln!("Reading input failed with error: {}", err);
+                break;
+            }
+        }
+        let line = line.trim();
+
+        if line.is_empty() {
+            continue;
+        }
+
+        if line.starts_with(|c: char| c.is_ascii_digit()) {
+            match lexer::tokenize_line(line) {
+                Ok(code) if code.tokens.is_empty() => {
+                    program.remove(&code.line_number);
+                }
+                Ok(code) => {
+                    program.insert(code.line_number, code);
+                }
+                Err(e) => report_lex_error(line, 0, &e),
+            }
+            continue;
+        }
+
+        let code_lines: Vec<lexer::LineOfCode> = program.values().cloned().collect();
+
+        let result = match line.to_uppercase().as_str() {
+            "RUN" => interpreter.run(&code_lines).map(|msg| msg + "\n"),
+            "CONT" => interpreter.cont(&code_lines).map(|msg| msg + "\n"),
+            "LIST" => {
+                for code in program.values() {
+                    println!("{}", lexer::format_line(code));
+                }
+                continue;
+            }
+            "NEW" => {
+                program.clear();
+                interpreter = new_interpreter();
+                continue;
+            }
+            _ => match lexer::tokenize_unnumbered_line(line, lexer::LineNumber(0)) {
+                // Only the line break a PRINT may have left open is worth showing
+                Ok(code) => interpreter.execute_direct(&code).map(|msg| if msg.starts_with('\n') { "\n" } else { "" }.to_string()),
+                Err(e) => {
+                    report_lex_error(line, 0, &e);
+                    continue;
+                }
+            },
+        };
+
+        match result {
+            Ok(msg) => print!("{}", msg),
+            Err(err) => eprintln!("Execution failed at {}:{} because: {}", err.line.0, err.pos, err.message),
+        }
+    }
+
+    if interactive {
+        println!();
+    }
+}
+
 fn main() {
-    let mut argv = env::args();
+    let mut json = false;
+    let mut coverage = false;
+    let mut allow_shell = false;
+    let mut input_prompt = false;
+    let mut line_trace = false;
+    let mut step_limit = None;
+    let mut timeout = None;
+    let mut numbering = lexer::Numbering::Numbered;
+    let mut defines: HashSet<String> = HashSet::new();
+    let mut program: Option<String> = None;
+
+    for arg in env::args().skip(1) {
+        match arg.as_str() {
+            "--json" => json = true,
+            "--coverage" => coverage = true,
+            "--allow-shell" => allow_shell = true,
+            "--input-prompt" => input_prompt = true,
+            "--tron" => line_trace = true,
+            "--unnumbered" => numbering = lexer::Numbering::Sequential,
+            limit if limit.starts_with("--max-steps=") => match limit["--max-steps=".len()..].parse() {
+                Ok(limit) => step_limit = Some(limit),
+                Err(_) => {
+                    eprintln!("Invalid step limit in {}", limit);
+                    exit(1);
+                }
+            },
+            limit if limit.starts_with("--timeout=") => match limit["--timeout=".len()..].parse::<f64>() {
+                Ok(seconds) if seconds.is_finite() && seconds >= 0.0 => timeout = Some(Duration::from_secs_f64(seconds)),
+                _ => {
+                    eprintln!("Invalid timeout in {}", limit);
+                    exit(1);
+                }
+            },
+            // -DNAME defines NAME for #IFDEF
+            define if define.starts_with("-D") && define.len() > 2 => {
+                defines.insert(define[2..].to_string());
+            }
+            _ => program = Some(arg),
+        }
+    }
 
     let ist = Instant::now();
 
-    if env::args().len() > 1 {
-        let program: String = argv.nth(1).unwrap();
+    let new_interpreter = || {
+        evaluator::Interpreter::builder()
+            .echo_input(!io::stdin().is_terminal())
+            .input_prompt(input_prompt)
+            .numbering(numbering)
+            .line_trace(line_trace)
+            .step_limit(step_limit)
+            .timeout(timeout)
+            .coverage(coverage)
+            .allow_shell(allow_shell)
+            .build()
+    };
+
+    if let Some(program) = program {
         match read_file(program.as_str()) {
             Ok(s) => {
-                let mut code_lines: Vec<lexer::LineOfCode> = Vec::new();
-
-                for (lineno, line) in s.lines().enumerate() {
-                    let result = lexer::tokenize_line(line);
-                    match result {
-                        Ok(x) => {
-                            if x.line_number.0 != u32::MAX - 1 {
-                                code_lines.push(x.clone());
-                                //println!("Tokens: {:?}", x);
-                            }
-                        },
-                        Err(e) => {
+                let s = match lexer::preprocess(&s, &defines) {
+                    Ok(s) => s,
+                    Err((lineno, e)) => {
+                        if json {
+                            let diagnostic = diagnostic::Diagnostic {
+                                line: lineno,
+                                column: None,
+                                severity: diagnostic::Severity::Error,
+                                message: e,
+                                code: "preprocess-error",
+                            };
+                            println!("{}", diagnostic::to_json(&[diagnostic]));
+                        } else {
                             eprintln!("Error at line {}: {}", lineno, e);
-                            exit(1);
                         }
+                        exit(1);
+                    }
+                };
+
+                if json {
+                    // Only report diagnostics, don't run the program
+                    let diagnostics = diagnostic::check_program(&s, numbering);
+                    println!("{}", diagnostic::to_json(&diagnostics));
+                    if diagnostics.iter().any(|d| d.severity == diagnostic::Severity::Error) {
+                        exit(1);
+                    }
+                    return;
+                }
+
+                let code_lines = match lexer::tokenize_program_with(&s, numbering) {
+                    Ok(code_lines) => code_lines,
+                    Err(e) => {
+                        report_lex_error(s.lines().nth(e.line).unwrap_or(""), e.line, &e.error);
+                        exit(1);
+                    }
+                };
+
+                let mut interpreter = new_interpreter();
+
+                let result = interpreter.run(&code_lines);
+
+                if let Some(counts) = interpreter.coverage() {
+                    eprintln!("Coverage:");
+                    for (line_number, count) in counts {
+                        eprintln!("{}\t{}", line_number.0, count);
                     }
                 }
 
-                match evaluator::evaluate(code_lines) {
+                match result {
                     Ok(msg) => println!("{} in {:?}", msg, ist.elapsed()),
                     Err(err) => {
-                        eprintln!("Execution failed at {}:{} because: {}", err.0.0, err.1, err.2);
+                        eprintln!("Execution failed at {}:{} because: {}", err.line.0, err.pos, err.message);
                         exit(1);
                     },
                 }
@@ -47,5 +223,7 @@ fn main() {
             }
             Err(err) => eprintln!("Getting file contents failed with error: {}", err),
         };
+    } else {
+        repl(&new_interpreter);
     }
 }
@@ -0,0 +1,167 @@
+use crate::error::{Error, ErrorKind};
+
+// Monochrome raster that SCREEN, PSET and LINE draw into. (0, 0) is the top
+// left corner, x grows to the right and y downwards
+#[derive(Debug, Clone, PartialEq)]
+pub struct Screen {
+    width: usize,
+    height: usize,
+    pixels: Vec<bool>,
+}
+
+// Most pixels a screen may have, so SCREEN 1E9, 1E9 is an error rather than an
+// attempt to allocate an exabyte
+const MAX_PIXELS: usize = 1 << 24;
+
+impl Screen {
+    pub fn new(width: usize, height: usize) -> Result<Screen, Error> {
+        match width.checked_mul(height) {
+            Some(pixels) if pixels <= MAX_PIXELS => Ok(Screen {
+                width,
+                height,
+                pixels: vec![false; pixels],
+            }),
+            _ => Err(Error::new(
+                ErrorKind::IllegalFunctionCall,
+                format!("A {}x{} screen is too large, at most {} pixels are allowed", width, height, MAX_PIXELS),
+            )),
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    // False for points off the screen
+    pub fn get(&self, x: i64, y: i64) -> bool {
+        match self.index(x, y) {
+            Some(index) => self.pixels[index],
+            None => false,
+        }
+    }
+
+    pub fn set(&mut self, x: i64, y: i64) -> Result<(), Error> {
+        match self.index(x, y) {
+            Some(index) => {
+                self.pixels[index] = true;
+                Ok(())
+            }
+            None => Err(Error::new(ErrorKind::IllegalFunctionCall, format!(
+                "Point ({}, {}) is off the {}x{} screen",
+                x, y, self.width, self.height
+            ))),
+        }
+    }
+
+    // Bresenham's line, both ends included. As the screen is convex it is
+    // enough that both ends are on it
+    pub fn line(&mut self, (x1, y1): (i64, i64), (x2, y2): (i64, i64)) -> Result<(), Error> {
+        for &(x, y) in [(x1, y1), (x2, y2)].iter() {
+            if self.index(x, y).is_none() {
+                return Err(Error::new(ErrorKind::IllegalFunctionCall, format!(
+                    "Point ({}, {}) is off the {}x{} screen",
+                    x, y, self.width, self.height
+                )));
+            }
+        }
+
+        let dx = (x2 - x1).abs();
+        let dy = -(y2 - y1).abs();
+        let sx = if x1 < x2 { 1 } else { -1 };
+        let sy = if y1 < y2 { 1 } else { -1 };
+        let mut error = dx + dy;
+        let (mut x, mut y) = (x1, y1);
+
+        loop {
+            self.set(x, y)?;
+            if x == x2 && y == y2 {
+                break;
+            }
+
+            let doubled = 2 * error;
+            if doubled >= dy {
+                error += dy;
+                x += sx;
+            }
+            if doubled <= dx {
+                error += dx;
+                y += sy;
+            }
+        }
+
+        Ok(())
+    }
+
+    // One row per line, `#` for set pixels and `.` for clear ones
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+
+        for row in self.pixels.chunks(self.width.max(1)).take(self.height) {
+            text.extend(row.iter().map(|&pixel| if pixel { '#' } else { '.' }));
+            text.push('\n');
+        }
+
+        text
+    }
+
+    // Plain (P3) PPM, set pixels are white on black
+    pub fn to_ppm(&self) -> String {
+        let mut ppm = format!("P3\n{} {}\n255\n", self.width, self.height);
+
+        for row in self.pixels.chunks(self.width.max(1)).take(self.height) {
+            let values: Vec<&str> = row
+                .iter()
+                .map(|&pixel| if pixel { "255 255 255" } else { "0 0 0" })
+                .collect();
+            ppm.push_str(&values.join(" "));
+            ppm.push('\n');
+        }
+
+        ppm
+    }
+
+    fn index(&self, x: i64, y: i64) -> Option<usize> {
+        if x < 0 || y < 0 || x as usize >= self.width || y as usize >= self.height {
+            return None;
+        }
+
+        Some(y as usize * self.width + x as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn set_and_line_draw_pixels() {
+        let mut screen = Screen::new(4, 3).unwrap();
+        screen.set(3, 0).unwrap();
+        screen.line((0, 2), (2, 0)).unwrap();
+
+        assert!(screen.get(3, 0));
+        assert!(screen.get(1, 1));
+        assert!(!screen.get(0, 0));
+        assert!(!screen.get(-1, 0));
+        assert_eq!(screen.to_text(), "..##\n.#..\n#...\n");
+    }
+
+    #[test]
+    fn points_off_the_screen_are_errors() {
+        let mut screen = Screen::new(2, 2).unwrap();
+        assert_eq!(screen.set(2, 0).unwrap_err().message, "Point (2, 0) is off the 2x2 screen");
+        assert!(screen.line((0, 0), (0, 5)).is_err());
+        assert_eq!(screen.to_text(), "..\n..\n");
+    }
+
+    #[test]
+    fn ppm_has_a_header_and_a_row_per_line() {
+        let mut screen = Screen::new(2, 1).unwrap();
+        screen.set(1, 0).unwrap();
+        assert_eq!(screen.to_ppm(), "P3\n2 1\n255\n0 0 0 255 255 255\n");
+    }
+}
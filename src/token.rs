@@ -1,3 +1,5 @@
+use std::fmt;
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum Token {
     Comment(String),
@@ -6,6 +8,19 @@ pub enum Token {
     Number(f64),
     BString(String),
     Srout(String),
+    // `name:` at the start of a line, a target for GOTO, GOSUB and THEN
+    Label(String),
+    Function(String),
+    Curline,
+    Err,
+    Erl,
+    Exitcode,
+    // Only produced by the expression parser: function name and argument count
+    Call(String, usize),
+    // Only produced by the expression parser: array name and subscript count
+    Index(String, usize),
+    // Only produced by the expression parser: DEF FN name and argument count
+    FnCall(String, usize),
 
     Equals,
     LessThan,
@@ -15,34 +30,158 @@ pub enum Token {
     NotEqual,
     Multiply,
     Divide,
+    IntDivide,
+    Mod,
+    Caret,
     Minus,
     Plus,
+    Ampersand,
+    And,
+    Or,
 
     Semicolon,
+    Comma,
     LParen,
     RParen,
+    LBrace,
+    RBrace,
 
     Bang,
     UMinus,
 
+    Cls,
+    Data,
+    Def,
+    Dim,
+    Dumptok,
+    Else,
+    End,
+    Error,
+    Eval,
     Goto,
     Gosub,
+    Fn,
     For,
     If,
+    In,
     Input,
     Let,
+    Local,
+    Line,
     Next,
+    On,
+    Precision,
     Print,
+    Pset,
+    Read,
     Rem,
+    Restore,
+    Resume,
     Return,
+    Screen,
+    Shell,
+    Spc,
     Step,
+    Stop,
     Sub,
+    Swap,
+    Tab,
     Then,
     To,
+    Troff,
+    Tron,
     Wend,
     While,
 }
 
+// Source text of a token, keywords in upper case and operators in the form the
+// lexer reads back to the same token (NOT for !, MOD for %)
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let text = match self {
+            Token::Comment(comment) => comment,
+            Token::Variable(name) | Token::Srout(name) | Token::Function(name) => name,
+            Token::Call(name, _) | Token::Index(name, _) => name,
+            Token::Number(number) => return write!(f, "{}", number),
+            Token::BString(string) => return write!(f, "\"{}\"", string),
+            Token::Label(name) => return write!(f, "{}:", name),
+            Token::FnCall(name, _) => return write!(f, "FN {}", name),
+            Token::Curline => "CURLINE",
+            Token::Err => "ERR",
+            Token::Erl => "ERL",
+            Token::Exitcode => "EXITCODE",
+            Token::Equals => "=",
+            Token::LessThan => "<",
+            Token::GreaterThan => ">",
+            Token::LessThanEqual => "<=",
+            Token::GreaterThanEqual => ">=",
+            Token::NotEqual => "<>",
+            Token::Multiply => "*",
+            Token::Divide => "/",
+            Token::IntDivide => "\\",
+            Token::Mod => "MOD",
+            Token::Caret => "^",
+            Token::Minus | Token::UMinus => "-",
+            Token::Plus => "+",
+            Token::Ampersand => "&",
+            Token::And => "AND",
+            Token::Or => "OR",
+            Token::Semicolon => ";",
+            Token::Comma => ",",
+            Token::LParen => "(",
+            Token::RParen => ")",
+            Token::LBrace => "{",
+            Token::RBrace => "}",
+            Token::Bang => "NOT",
+            Token::Cls => "CLS",
+            Token::Data => "DATA",
+            Token::Def => "DEF",
+            Token::Dim => "DIM",
+            Token::Dumptok => "DUMPTOK",
+            Token::Else => "ELSE",
+            Token::End => "END",
+            Token::Error => "ERROR",
+            Token::Eval => "EVAL",
+            Token::Goto => "GOTO",
+            Token::Gosub => "GOSUB",
+            Token::Fn => "FN",
+            Token::For => "FOR",
+            Token::If => "IF",
+            Token::In => "IN",
+            Token::Input => "INPUT",
+            Token::Let => "LET",
+            Token::Local => "LOCAL",
+            Token::Line => "LINE",
+            Token::Next => "NEXT",
+            Token::On => "ON",
+            Token::Precision => "PRECISION",
+            Token::Print => "PRINT",
+            Token::Pset => "PSET",
+            Token::Read => "READ",
+            Token::Rem => "REM",
+            Token::Restore => "RESTORE",
+            Token::Resume => "RESUME",
+            Token::Return => "RETURN",
+            Token::Screen => "SCREEN",
+            Token::Shell => "SHELL",
+            Token::Spc => "SPC",
+            Token::Step => "STEP",
+            Token::Stop => "STOP",
+            Token::Sub => "SUB",
+            Token::Swap => "SWAP",
+            Token::Tab => "TAB",
+            Token::Then => "THEN",
+            Token::To => "TO",
+            Token::Troff => "TROFF",
+            Token::Tron => "TRON",
+            Token::Wend => "WEND",
+            Token::While => "WHILE",
+        };
+
+        f.write_str(text)
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum Associativity {
     Left,
@@ -60,55 +199,91 @@ impl Token {
             "<>" => Some(Token::NotEqual),
             "*" => Some(Token::Multiply),
             "/" => Some(Token::Divide),
+            "\\" => Some(Token::IntDivide),
+            "^" => Some(Token::Caret),
+            "MOD" | "%" => Some(Token::Mod),
             // Yes, this is also Token::UMinus
             "-" => Some(Token::Minus),
             "+" => Some(Token::Plus),
+            "&" => Some(Token::Ampersand),
             ";" => Some(Token::Semicolon),
+            "," => Some(Token::Comma),
             "(" => Some(Token::LParen),
             ")" => Some(Token::RParen),
-            "!" => Some(Token::Bang),
+            "{" => Some(Token::LBrace),
+            "}" => Some(Token::RBrace),
+            "!" | "NOT" => Some(Token::Bang),
+            "AND" => Some(Token::And),
+            "OR" => Some(Token::Or),
+            "CLS" => Some(Token::Cls),
+            "DATA" => Some(Token::Data),
+            "DEF" => Some(Token::Def),
+            "DIM" => Some(Token::Dim),
+            "DUMPTOK" => Some(Token::Dumptok),
+            "ELSE" => Some(Token::Else),
+            "END" => Some(Token::End),
+            "ERROR" => Some(Token::Error),
+            "EVAL" => Some(Token::Eval),
             "GOSUB" => Some(Token::Gosub),
             "GOTO" => Some(Token::Goto),
+            "FN" => Some(Token::Fn),
             "FOR" => Some(Token::For),
             "IF" => Some(Token::If),
+            "IN" => Some(Token::In),
             "INPUT" => Some(Token::Input),
             "LET" => Some(Token::Let),
+            "LOCAL" => Some(Token::Local),
+            "LINE" => Some(Token::Line),
             "NEXT" => Some(Token::Next),
+            "ON" => Some(Token::On),
+            "PRECISION" => Some(Token::Precision),
             "PRINT" => Some(Token::Print),
+            "PSET" => Some(Token::Pset),
+            "READ" => Some(Token::Read),
             "REM" => Some(Token::Rem),
+            "RESTORE" => Some(Token::Restore),
+            "RESUME" => Some(Token::Resume),
             "RETURN" => Some(Token::Return),
+            "SCREEN" => Some(Token::Screen),
+            "SHELL" => Some(Token::Shell),
+            "SPC" => Some(Token::Spc),
             "STEP" => Some(Token::Step),
+            "STOP" => Some(Token::Stop),
             "SUB" => Some(Token::Sub),
+            "SWAP" => Some(Token::Swap),
+            "TAB" => Some(Token::Tab),
             "THEN" => Some(Token::Then),
             "TO" => Some(Token::To),
+            "TROFF" => Some(Token::Troff),
+            "TRON" => Some(Token::Tron),
             "WEND" => Some(Token::Wend),
             "WHILE" => Some(Token::While),
+            "CURLINE" => Some(Token::Curline),
+            "ERR" => Some(Token::Err),
+            "ERL" => Some(Token::Erl),
+            "EXITCODE" => Some(Token::Exitcode),
+            "ABS" | "ASC" | "ATN" | "BASE$" | "CHR$" | "COS" | "ENVIRON$" | "EXIST" | "EXP" | "INPUT$" | "INT" | "LEFT$" | "LEN" | "LOG" |
+            "MID$" | "RIGHT$" | "RND" | "SGN" | "SIN" | "SQR" | "STR$" | "TAN" | "TYPEOF" | "VAL" => Some(Token::Function(token_str.to_string())),
             _ => None,
         }
     }
 
     pub fn is_operator(&self) -> bool {
-        match *self {
+        matches!(*self,
             Token::Equals | Token::LessThan | Token::GreaterThan | Token::LessThanEqual |
             Token::GreaterThanEqual | Token::NotEqual | Token::Multiply | Token::Divide |
-            Token::Minus | Token::Plus | Token::UMinus | Token::Bang => true,
-            _ => false,
-        }
+            Token::IntDivide | Token::Mod | Token::Caret | Token::Minus | Token::Plus |
+            Token::Ampersand | Token::And | Token::Or | Token::UMinus | Token::Bang)
     }
 
     pub fn is_comparison_operator(&self) -> bool {
-        match *self {
+        matches!(*self,
             Token::Equals | Token::LessThan | Token::GreaterThan | Token::LessThanEqual |
-            Token::GreaterThanEqual | Token::NotEqual => true,
-            _ => false,
-        }
+            Token::GreaterThanEqual | Token::NotEqual)
     }
 
     pub fn is_unary_operator(&self) -> bool {
-        match *self {
-            Token::UMinus | Token::Bang => true,
-            _ => false,
-        }
+        matches!(*self, Token::UMinus | Token::Bang)
     }
 
     pub fn is_binary_operator(&self) -> bool {
@@ -116,12 +291,14 @@ impl Token {
     }
 
     pub fn is_value(&self) -> bool {
-        match *self {
+        matches!(*self,
             Token::Variable(_) |
             Token::Number(_) |
-            Token::BString(_) => true,
-            _ => false,
-        }
+            Token::BString(_) |
+            Token::Curline |
+            Token::Err |
+            Token::Erl |
+            Token::Exitcode)
     }
 
     pub fn operator_precedence(&self) -> Result<u8, String> {
@@ -130,16 +307,24 @@ impl Token {
         }
 
         match *self {
-            Token::UMinus | Token::Bang => Ok(12),
-            Token::Multiply | Token::Divide => Ok(10),
+            // Above unary minus so that -2 ^ 2 is -4
+            Token::Caret => Ok(13),
+            Token::UMinus => Ok(12),
+            Token::Multiply | Token::Divide | Token::IntDivide | Token::Mod => Ok(10),
             Token::Minus | Token::Plus => Ok(8),
+            // Below + so that "N = " & N + 1 concatenates the sum
+            Token::Ampersand => Ok(6),
+            // NOT sits below comparisons so NOT A = B negates the comparison
+            Token::Bang => Ok(3),
+            Token::And => Ok(2),
+            Token::Or => Ok(1),
             _ => Ok(4),
         }
     }
 
     pub fn operator_associavity(&self) -> Result<Associativity, String> {
         match *self {
-            Token::UMinus | Token::Bang => Ok(Associativity::Right),
+            Token::UMinus | Token::Bang | Token::Caret => Ok(Associativity::Right),
             _ => Ok(Associativity::Left),
         }
     }
@@ -1,188 +1,372 @@
+use crate::error::{Error, ErrorKind};
+
 use std::{
-    ops::{Add, Div, Mul, Neg, Not, Sub},
+    convert::TryFrom,
+    fmt,
+    ops::{Add, Div, Mul, Neg, Not, Rem, Sub},
     str::FromStr,
 };
 
 #[derive(Debug, Clone)]
 pub enum Value {
     String(String),
+    Int(i64),
     Number(f64),
     Bool(bool),
+    // Elements are stored row-major, `dims` holds the extent of each dimension
+    Array { dims: Vec<usize>, elements: Vec<Value> },
+}
+
+// Largest whole f64 that is still exact, 2^53
+const MAX_EXACT_INT: f64 = 9_007_199_254_740_992.0;
+
+// Sign convention of MOD when an operand is negative:
+// Truncated takes the sign of the dividend, (-7) MOD 3 = -1 (classic BASIC, C)
+// Floored takes the sign of the divisor, (-7) MOD 3 = 2 (Python)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ModMode {
+    Truncated,
+    Floored,
+}
+
+// How PRINT shows booleans: Words as true/false, Numeric as the classic -1/0
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BoolMode {
+    Words,
+    Numeric,
+}
+
+// Int and Number mix freely: an operation on two Ints stays an Int unless it
+// overflows, anything involving a Number is done in floating point. / is always
+// floating point, \ and MOD of two Ints are exact
+impl Value {
+    // A whole number as an Int, anything else as a Number. Literals come out of
+    // the lexer as f64, so 5 and 5.0 are both Ints
+    pub fn from_number(number: f64) -> Value {
+        if number.fract() == 0.0 && number.abs() <= MAX_EXACT_INT {
+            Value::Int(number as i64)
+        } else {
+            Value::Number(number)
+        }
+    }
+
+    // The value with an Int turned into the equivalent Number, for code that only
+    // cares about the numeric value
+    pub fn into_float(self) -> Value {
+        match self {
+            Value::Int(int) => Value::Number(int as f64),
+            value => value,
+        }
+    }
+
+    pub fn display(&self, bool_mode: BoolMode) -> String {
+        match (self, bool_mode) {
+            (Value::Bool(true), BoolMode::Numeric) => "-1".to_string(),
+            (Value::Bool(false), BoolMode::Numeric) => "0".to_string(),
+            (value, _) => value.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Value::String(ref string) => write!(f, "{}", string),
+            Value::Int(int) => write!(f, "{}", int),
+            Value::Number(number) => write!(f, "{}", format_number(number)),
+            Value::Bool(boolean) => write!(f, "{}", boolean),
+            Value::Array { ref dims, .. } => {
+                let sizes: Vec<String> = dims.iter().map(|dim| (dim - 1).to_string()).collect();
+                write!(f, "array({})", sizes.join(","))
+            }
+        }
+    }
+}
+
+// Significant digits shown when PRECISION isn't set, as many as an f64 always
+// holds exactly, so 0.1 + 0.2 shows as 0.3 rather than 0.30000000000000004
+pub const DEFAULT_DIGITS: usize = 15;
+
+// How PRINT and STR$ show a number: 5.0 as 5, 0.5 as 0.5, 1 / 3 as 0.333333333333333
+pub fn format_number(number: f64) -> String {
+    format_number_to(number, DEFAULT_DIGITS)
+}
+
+// Rounds to `digits` significant digits, dropping trailing zeros and the
+// decimal point of whole numbers. Like C's %g, numbers whose exponent is at
+// least `digits` or below -4 are shown in exponent notation, 1E+20 rather
+// than 21 digits of which only the first `digits` mean anything
+pub fn format_number_to(number: f64, digits: usize) -> String {
+    if !number.is_finite() {
+        return number.to_string();
+    }
+
+    // Rounding through scientific notation keeps the significant digits
+    // wherever the decimal point is
+    let digits = digits.max(1);
+    let rounded = format!("{:.*e}", digits - 1, number);
+    let (mantissa, exponent) = rounded.split_at(rounded.find('e').unwrap_or(rounded.len()));
+    let exponent = i32::from_str(exponent.trim_start_matches('e')).unwrap_or(0);
+
+    if exponent < -4 || exponent >= digits as i32 {
+        let mantissa = if mantissa.contains('.') {
+            mantissa.trim_end_matches('0').trim_end_matches('.')
+        } else {
+            mantissa
+        };
+        let sign = if exponent < 0 { '-' } else { '+' };
+        return format!("{}E{}{:02}", mantissa, sign, exponent.abs());
+    }
+
+    // Adding 0 turns -0 into 0
+    (f64::from_str(&rounded).unwrap_or(number) + 0.0).to_string()
 }
 
 // -----------------------------------------------
 // Implementations of unary operators
 impl Neg for Value {
-    type Output = Result<Value, String>;
+    type Output = Result<Value, Error>;
 
     fn neg(self) -> Self::Output {
         match self {
+            Value::Int(int) => Ok(int.checked_neg().map_or(Value::Number(-(int as f64)), Value::Int)),
             Value::Number(ref number) => Ok(Value::Number(-*number)),
-            _ => Err("Cannot negate non-numeric values!".to_string()),
+            _ => Err(Error::new(ErrorKind::TypeMismatch, "Cannot negate non-numeric values!".to_string())),
         }
     }
 }
 
 impl Not for Value {
-    type Output = Result<Value, String>;
+    type Output = Result<Value, Error>;
 
     fn not(self) -> Self::Output {
         match self {
             Value::Bool(ref boolean) => Ok(Value::Bool(!boolean)),
-            _ => Err("Cannot apply unary not to non-Boolean values.".to_string()),
+            _ => Err(Error::new(ErrorKind::TypeMismatch, "Cannot apply unary not to non-Boolean values.".to_string())),
         }
     }
 }
 
 // -----------------------------------------------
 // Implementations of binary operators
-impl Add for Value {
-    type Output = Result<Value, String>;
-
-    fn add(self, other: Value) -> Self::Output {
-        match (self, other) {
-            (Value::Number(number1), Value::Number(number2)) => {
-                Ok(Value::Number(number1 + number2))
-            }
-            (Value::String(string1), Value::String(string2)) => {
-                Ok(Value::String(format!("{}{}", string1, string2)))
-            }
-            (Value::Number(number1), Value::String(string2)) => {
-                let number2 = f64::from_str(string2.as_str());
+//
+// Operand rules, shared by + - * / \ MOD ^:
+// number op number    arithmetic, see above for Int and Number
+// string + string     concatenation, the only string-only operation
+// number op string    the string is read as a number, error if it isn't one
+// string op number    same as above
+// anything else       error
+// & always concatenates and accepts numbers as well as strings
+impl Value {
+    pub fn describe(&self) -> String {
+        match *self {
+            Value::String(ref string) => format!("string \"{}\"", string),
+            Value::Int(int) => format!("integer {}", int),
+            Value::Number(number) => format!("number {}", number),
+            Value::Bool(boolean) => format!("boolean {}", boolean),
+            Value::Array { .. } => "array".to_string(),
+        }
+    }
 
-                if let Result::Ok(number2_value) = number2 {
-                    Ok(Value::Number(number1 + number2_value))
-                } else {
-                    Err(format!(
-                        "Cannot add integer {} and string {}",
-                        number1, string2
-                    ))
+    // Coerces both operands of an arithmetic operator to numbers
+    fn numeric_operands(self, other: Value, operation: &str) -> Result<(f64, f64), Error> {
+        let as_number = |value: &Value| match *value {
+            Value::Int(int) => Some(int as f64),
+            Value::Number(number) => Some(number),
+            Value::String(ref string) => f64::from_str(string.as_str()).ok(),
+            _ => None,
+        };
+
+        match (&self, &other) {
+            (Value::String(_), Value::String(_)) => {}
+            (first, second) => {
+                if let (Some(number1), Some(number2)) = (as_number(first), as_number(second)) {
+                    return Ok((number1, number2));
                 }
             }
-            (Value::String(string1), Value::Number(number2)) => {
-                let number1 = f64::from_str(string1.as_str());
+        }
 
-                if let Result::Ok(number1_value) = number1 {
-                    Ok(Value::Number(number1_value + number2))
-                } else {
-                    Err(format!(
-                        "Cannot add string {} and integer {}",
-                        string1, number2
-                    ))
-                }
+        Err(Error::new(
+            ErrorKind::TypeMismatch,
+            format!("Cannot {} {} and {}", operation, self.describe(), other.describe()),
+        ))
+    }
+
+    pub fn concat(self, other: Value) -> Result<Value, Error> {
+        match (self, other) {
+            (Value::String(string1), Value::String(string2)) => Ok(Value::String(string1 + &string2)),
+            (
+                first @ (Value::String(_) | Value::Int(_) | Value::Number(_)),
+                second @ (Value::String(_) | Value::Int(_) | Value::Number(_)),
+            ) => {
+                Ok(Value::String(format!("{}{}", first, second)))
             }
-            _ => Err("Can only add integers or concatenate strings.".to_string()),
+            (first, second) => Err(Error::new(
+                ErrorKind::TypeMismatch,
+                format!("Cannot concatenate {} and {}", first.describe(), second.describe()),
+            )),
         }
     }
 }
 
-impl Div for Value {
-    type Output = Result<Value, String>;
+impl Add for Value {
+    type Output = Result<Value, Error>;
 
-    fn div(self, other: Value) -> Self::Output {
+    fn add(self, other: Value) -> Self::Output {
         match (self, other) {
-            (Value::Number(number1), Value::Number(number2)) => {
-                Ok(Value::Number(number1 / number2))
+            (Value::String(string1), Value::String(string2)) => Ok(Value::String(string1 + &string2)),
+            (Value::Int(int1), Value::Int(int2)) => {
+                Ok(int1.checked_add(int2).map_or(Value::Number(int1 as f64 + int2 as f64), Value::Int))
             }
-            (Value::Number(number1), Value::String(string2)) => {
-                let number2 = f64::from_str(string2.as_str());
-
-                if let Result::Ok(number2_value) = number2 {
-                    Ok(Value::Number(number1 / number2_value))
-                } else {
-                    Err(format!(
-                        "Cannot divide integer {} and string {}",
-                        number1, string2
-                    ))
-                }
+            (first, second) => {
+                let (number1, number2) = first.numeric_operands(second, "add")?;
+                Ok(Value::Number(number1 + number2))
             }
-            (Value::String(string1), Value::Number(number2)) => {
-                let number1 = f64::from_str(string1.as_str());
+        }
+    }
+}
 
-                if let Result::Ok(number1_value) = number1 {
-                    Ok(Value::Number(number1_value / number2))
-                } else {
-                    Err(format!(
-                        "Cannot divide string {} and integer {}",
-                        string1, number2
-                    ))
-                }
-            }
-            _ => Err("Can only divide integers.".to_string()),
+impl Div for Value {
+    type Output = Result<Value, Error>;
+
+    fn div(self, other: Value) -> Self::Output {
+        let (number1, number2) = self.numeric_operands(other, "divide")?;
+        // 0 / 0 too, rather than NaN
+        if number2 == 0.0 {
+            return Err(Error::new(ErrorKind::DivisionByZero, format!("Division by zero in {} / 0", number1)));
         }
+        Ok(Value::Number(number1 / number2))
     }
 }
 
 impl Mul for Value {
-    type Output = Result<Value, String>;
+    type Output = Result<Value, Error>;
 
     fn mul(self, other: Value) -> Self::Output {
-        match (self, other) {
-            (Value::Number(number1), Value::Number(number2)) => {
-                Ok(Value::Number(number1 * number2))
-            }
-            (Value::Number(number1), Value::String(string2)) => {
-                let number2 = f64::from_str(string2.as_str());
-
-                if let Result::Ok(number2_value) = number2 {
-                    Ok(Value::Number(number1 * number2_value))
-                } else {
-                    Err(format!(
-                        "Cannot multiply integer {} and string {}",
-                        number1, string2
-                    ))
-                }
-            }
-            (Value::String(string1), Value::Number(number2)) => {
-                let number1 = f64::from_str(string1.as_str());
+        if let (Value::Int(int1), Value::Int(int2)) = (&self, &other) {
+            return Ok(int1.checked_mul(*int2).map_or(Value::Number(*int1 as f64 * *int2 as f64), Value::Int));
+        }
+        let (number1, number2) = self.numeric_operands(other, "multiply")?;
+        Ok(Value::Number(number1 * number2))
+    }
+}
 
-                if let Result::Ok(number1_value) = number1 {
-                    Ok(Value::Number(number1_value * number2))
-                } else {
-                    Err(format!(
-                        "Cannot multiply string {} and integer {}",
-                        string1, number2
-                    ))
-                }
-            }
-            _ => Err("Can only multiply integers.".to_string()),
+impl Rem for Value {
+    type Output = Result<Value, Error>;
+
+    // Truncated remainder, see Value::modulo for the floored variant
+    fn rem(self, other: Value) -> Self::Output {
+        if let (Value::Int(int1), Value::Int(int2)) = (&self, &other) {
+            return match int1.checked_rem(*int2) {
+                Some(remainder) => Ok(Value::Int(remainder)),
+                None if *int2 == 0 => Err(Error::new(
+                    ErrorKind::DivisionByZero,
+                    format!("Division by zero in {} MOD 0", int1),
+                )),
+                // i64::MIN MOD -1
+                None => Ok(Value::Int(0)),
+            };
         }
+        let (number1, number2) = self.numeric_operands(other, "take the modulo of")?;
+        if number2 == 0.0 {
+            return Err(Error::new(ErrorKind::DivisionByZero, format!("Division by zero in {} MOD 0", number1)));
+        }
+        Ok(Value::Number(number1 % number2))
     }
 }
 
 impl Sub for Value {
-    type Output = Result<Value, String>;
+    type Output = Result<Value, Error>;
 
     fn sub(self, other: Value) -> Self::Output {
+        if let (Value::Int(int1), Value::Int(int2)) = (&self, &other) {
+            return Ok(int1.checked_sub(*int2).map_or(Value::Number(*int1 as f64 - *int2 as f64), Value::Int));
+        }
+        let (number1, number2) = self.numeric_operands(other, "subtract")?;
+        Ok(Value::Number(number1 - number2))
+    }
+}
+
+impl Value {
+    // Both sides are always evaluated, there is no short-circuiting
+    pub fn and(self, other: Value) -> Result<Value, Error> {
         match (self, other) {
-            (Value::Number(number1), Value::Number(number2)) => {
-                Ok(Value::Number(number1 - number2))
-            }
-            (Value::Number(number1), Value::String(string2)) => {
-                let number2 = f64::from_str(string2.as_str());
+            (Value::Bool(bool1), Value::Bool(bool2)) => Ok(Value::Bool(bool1 && bool2)),
+            (first, second) => Err(Error::new(
+                ErrorKind::TypeMismatch,
+                format!("Cannot apply AND to {} and {}", first.describe(), second.describe()),
+            )),
+        }
+    }
 
-                if let Result::Ok(number2_value) = number2 {
-                    Ok(Value::Number(number1 - number2_value))
-                } else {
-                    Err(format!(
-                        "Cannot subtract integer {} from string {}",
-                        number1, string2
-                    ))
-                }
+    pub fn or(self, other: Value) -> Result<Value, Error> {
+        match (self, other) {
+            (Value::Bool(bool1), Value::Bool(bool2)) => Ok(Value::Bool(bool1 || bool2)),
+            (first, second) => Err(Error::new(
+                ErrorKind::TypeMismatch,
+                format!("Cannot apply OR to {} and {}", first.describe(), second.describe()),
+            )),
+        }
+    }
+
+    // Truncates towards zero, 7 \ -2 = -3
+    pub fn int_div(self, other: Value) -> Result<Value, Error> {
+        if let (Value::Int(int1), Value::Int(int2)) = (&self, &other) {
+            return match int1.checked_div(*int2) {
+                Some(quotient) => Ok(Value::Int(quotient)),
+                None if *int2 == 0 => Err(Error::new(
+                    ErrorKind::DivisionByZero,
+                    format!("Division by zero in {} \\ 0", int1),
+                )),
+                // i64::MIN \ -1
+                None => Ok(Value::Number(-(*int1 as f64))),
+            };
+        }
+        let (number1, number2) = self.numeric_operands(other, "divide")?;
+        if number2 == 0.0 {
+            return Err(Error::new(ErrorKind::DivisionByZero, format!("Division by zero in {} \\ 0", number1)));
+        }
+        Ok(Value::Number((number1 / number2).trunc()))
+    }
+
+    pub fn pow(self, other: Value) -> Result<Value, Error> {
+        // A negative exponent gives a fraction, so that stays floating point
+        if let (Value::Int(base), Value::Int(exponent)) = (&self, &other) {
+            if let Some(result) = u32::try_from(*exponent).ok().and_then(|exponent| base.checked_pow(exponent)) {
+                return Ok(Value::Int(result));
             }
-            (Value::String(string1), Value::Number(number2)) => {
-                let number1 = f64::from_str(string1.as_str());
+        }
+        let (base, exponent) = self.numeric_operands(other, "exponentiate")?;
+        let result = base.powf(exponent);
 
-                if let Result::Ok(number1_value) = number1 {
-                    Ok(Value::Number(number1_value - number2))
-                } else {
-                    Err(format!(
-                        "Cannot subtract string {} from integer {}",
-                        string1, number2
-                    ))
-                }
+        if result.is_nan() && !base.is_nan() && !exponent.is_nan() {
+            return Err(Error::new(ErrorKind::IllegalFunctionCall, format!("{} ^ {} has no real result", base, exponent)));
+        }
+        Ok(Value::Number(result))
+    }
+
+    pub fn modulo(self, other: Value, mode: ModMode) -> Result<Value, Error> {
+        let divisor = match other {
+            Value::Int(int) => int as f64,
+            Value::Number(number) => number,
+            Value::String(ref string) => f64::from_str(string.as_str()).unwrap_or(f64::NAN),
+            Value::Bool(_) | Value::Array { .. } => f64::NAN,
+        };
+
+        match (self % other, mode) {
+            (Ok(Value::Number(remainder)), ModMode::Floored)
+                if remainder != 0.0 && (remainder < 0.0) != (divisor < 0.0) =>
+            {
+                Ok(Value::Number(remainder + divisor))
+            }
+            // The divisor is an Int here, and adding it can't overflow as the
+            // remainder has the opposite sign
+            (Ok(Value::Int(remainder)), ModMode::Floored)
+                if remainder != 0 && (remainder < 0) != (divisor < 0.0) =>
+            {
+                Ok(Value::Int(remainder + divisor as i64))
             }
-            _ => Err("Can only subtract integers.".to_string()),
+            (result, _) => result,
         }
     }
 }
@@ -190,131 +374,414 @@ impl Sub for Value {
 // -----------------------------------------------
 // Implementations of binary comparison operators
 impl Value {
-    pub fn eq(&self, other: &Value) -> Result<bool, String> {
+    pub fn eq(&self, other: &Value) -> Result<bool, Error> {
         match (self, other) {
+            (&Value::Int(int1), &Value::Int(int2)) => Ok(int1 == int2),
+            (Value::Int(_), _) | (_, Value::Int(_)) => self.clone().into_float().eq(&other.clone().into_float()),
             (&Value::Number(number1), &Value::Number(number2)) => {
                 Ok(number1 == number2)
             }
-            (&Value::String(ref string1), &Value::String(ref string2)) => {
+            (Value::String(string1), Value::String(string2)) => {
                 Ok(string1 == string2)
             }
             (&Value::Bool(bool1), &Value::Bool(bool2)) => Ok(bool1 == bool2),
-            (&Value::Number(number1), &Value::String(ref string2)) => {
+            (&Value::Number(number1), Value::String(string2)) => {
                 let number2 = f64::from_str(string2.as_str());
 
                 if let Result::Ok(number2_value) = number2 {
                     Ok(number1 == number2_value)
                 } else {
-                    Err(format!(
+                    Err(Error::new(ErrorKind::TypeMismatch, format!(
                         "Cannot compare integer {} from string {}",
                         number1, string2
-                    ))
+                    )))
                 }
             }
-            (&Value::String(ref string1), &Value::Number(number2)) => {
+            (Value::String(string1), &Value::Number(number2)) => {
                 let number1 = f64::from_str(string1.as_str());
 
                 if let Result::Ok(number1_value) = number1 {
                     Ok(number1_value == number2)
                 } else {
-                    Err(format!(
+                    Err(Error::new(ErrorKind::TypeMismatch, format!(
                         "Cannot compare string {} and integer {}",
                         string1, number2
-                    ))
+                    )))
                 }
             }
-            _ => Err(format!(
+            _ => Err(Error::new(ErrorKind::TypeMismatch, format!(
                 "Cannot compare values of different types {:?} and {:?}",
                 *self, *other
-            )),
+            ))),
         }
     }
 
-    pub fn neq(&self, other: &Value) -> Result<bool, String> {
+    pub fn neq(&self, other: &Value) -> Result<bool, Error> {
         self.eq(other).map(|value| !value)
     }
 
-    pub fn lt(&self, other: &Value) -> Result<bool, String> {
+    pub fn lt(&self, other: &Value) -> Result<bool, Error> {
         match (self, other) {
+            (&Value::Int(int1), &Value::Int(int2)) => Ok(int1 < int2),
+            (Value::Int(_), _) | (_, Value::Int(_)) => self.clone().into_float().lt(&other.clone().into_float()),
             (&Value::Number(number1), &Value::Number(number2)) => Ok(number1 < number2),
-            (&Value::String(ref string1), &Value::String(ref string2)) => {
+            (Value::String(string1), Value::String(string2)) => {
                 Ok(string1 < string2)
             }
-            (&Value::Bool(bool1), &Value::Bool(bool2)) => Ok(bool1 == bool2),
-            (&Value::Number(number1), &Value::String(ref string2)) => {
+            // false < true
+            (&Value::Bool(bool1), &Value::Bool(bool2)) => Ok(!bool1 && bool2),
+            (&Value::Number(number1), Value::String(string2)) => {
                 let number2 = f64::from_str(string2.as_str());
 
                 if let Result::Ok(number2_value) = number2 {
                     Ok(number1 < number2_value)
                 } else {
-                    Err(format!(
+                    Err(Error::new(ErrorKind::TypeMismatch, format!(
                         "Cannot compare integer {} from string {}",
                         number1, string2
-                    ))
+                    )))
                 }
             }
-            (&Value::String(ref string1), &Value::Number(number2)) => {
+            (Value::String(string1), &Value::Number(number2)) => {
                 let number1 = f64::from_str(string1.as_str());
 
                 if let Result::Ok(number1_value) = number1 {
                     Ok(number1_value < number2)
                 } else {
-                    Err(format!(
+                    Err(Error::new(ErrorKind::TypeMismatch, format!(
                         "Cannot compare string {} and integer {}",
                         string1, number2
-                    ))
+                    )))
                 }
             }
-            _ => Err(format!(
+            _ => Err(Error::new(ErrorKind::TypeMismatch, format!(
                 "Cannot compare values of different types {:?} and {:?}",
                 *self, *other
-            )),
+            ))),
         }
     }
 
-    pub fn gt(&self, other: &Value) -> Result<bool, String> {
+    pub fn gt(&self, other: &Value) -> Result<bool, Error> {
         match (self, other) {
+            (&Value::Int(int1), &Value::Int(int2)) => Ok(int1 > int2),
+            (Value::Int(_), _) | (_, Value::Int(_)) => self.clone().into_float().gt(&other.clone().into_float()),
             (&Value::Number(number1), &Value::Number(number2)) => Ok(number1 > number2),
-            (&Value::String(ref string1), &Value::String(ref string2)) => {
+            (Value::String(string1), Value::String(string2)) => {
                 Ok(string1 > string2)
             }
             (&Value::Bool(bool1), &Value::Bool(bool2)) => Ok(bool1 && !bool2),
-            (&Value::Number(number1), &Value::String(ref string2)) => {
+            (&Value::Number(number1), Value::String(string2)) => {
                 let number2 = f64::from_str(string2.as_str());
 
                 if let Result::Ok(number2_value) = number2 {
                     Ok(number1 > number2_value)
                 } else {
-                    Err(format!(
+                    Err(Error::new(ErrorKind::TypeMismatch, format!(
                         "Cannot compare integer {} from string {}",
                         number1, string2
-                    ))
+                    )))
                 }
             }
-            (&Value::String(ref string1), &Value::Number(number2)) => {
+            (Value::String(string1), &Value::Number(number2)) => {
                 let number1 = f64::from_str(string1.as_str());
 
                 if let Result::Ok(number1_value) = number1 { 
                     Ok(number1_value > number2)
                 } else {
-                    Err(format!(
+                    Err(Error::new(ErrorKind::TypeMismatch, format!(
                         "Cannot compare string {} and integer {}",
                         string1, number2
-                    ))
+                    )))
                 }
             }
-            _ => Err(format!(
+            _ => Err(Error::new(ErrorKind::TypeMismatch, format!(
                 "Cannot compare values of different types {:?} and {:?}",
                 *self, *other
-            )),
+            ))),
+        }
+    }
+
+    pub fn lteq(&self, other: &Value) -> Result<bool, Error> {
+        match (self, other) {
+            (&Value::Int(int1), &Value::Int(int2)) => Ok(int1 <= int2),
+            (Value::Int(_), _) | (_, Value::Int(_)) => self.clone().into_float().lteq(&other.clone().into_float()),
+            (&Value::Number(number1), &Value::Number(number2)) => Ok(number1 <= number2),
+            (Value::String(string1), Value::String(string2)) => {
+                Ok(string1 <= string2)
+            }
+            (&Value::Bool(bool1), &Value::Bool(bool2)) => Ok(!bool1 || bool2),
+            (&Value::Number(number1), Value::String(string2)) => {
+                let number2 = f64::from_str(string2.as_str());
+
+                if let Result::Ok(number2_value) = number2 {
+                    Ok(number1 <= number2_value)
+                } else {
+                    Err(Error::new(ErrorKind::TypeMismatch, format!(
+                        "Cannot compare integer {} from string {}",
+                        number1, string2
+                    )))
+                }
+            }
+            (Value::String(string1), &Value::Number(number2)) => {
+                let number1 = f64::from_str(string1.as_str());
+
+                if let Result::Ok(number1_value) = number1 {
+                    Ok(number1_value <= number2)
+                } else {
+                    Err(Error::new(ErrorKind::TypeMismatch, format!(
+                        "Cannot compare string {} and integer {}",
+                        string1, number2
+                    )))
+                }
+            }
+            _ => Err(Error::new(ErrorKind::TypeMismatch, format!(
+                "Cannot compare values of different types {:?} and {:?}",
+                *self, *other
+            ))),
+        }
+    }
+
+    pub fn gteq(&self, other: &Value) -> Result<bool, Error> {
+        match (self, other) {
+            (&Value::Int(int1), &Value::Int(int2)) => Ok(int1 >= int2),
+            (Value::Int(_), _) | (_, Value::Int(_)) => self.clone().into_float().gteq(&other.clone().into_float()),
+            (&Value::Number(number1), &Value::Number(number2)) => Ok(number1 >= number2),
+            (Value::String(string1), Value::String(string2)) => {
+                Ok(string1 >= string2)
+            }
+            (&Value::Bool(bool1), &Value::Bool(bool2)) => Ok(bool1 || !bool2),
+            (&Value::Number(number1), Value::String(string2)) => {
+                let number2 = f64::from_str(string2.as_str());
+
+                if let Result::Ok(number2_value) = number2 {
+                    Ok(number1 >= number2_value)
+                } else {
+                    Err(Error::new(ErrorKind::TypeMismatch, format!(
+                        "Cannot compare integer {} from string {}",
+                        number1, string2
+                    )))
+                }
+            }
+            (Value::String(string1), &Value::Number(number2)) => {
+                let number1 = f64::from_str(string1.as_str());
+
+                if let Result::Ok(number1_value) = number1 {
+                    Ok(number1_value >= number2)
+                } else {
+                    Err(Error::new(ErrorKind::TypeMismatch, format!(
+                        "Cannot compare string {} and integer {}",
+                        string1, number2
+                    )))
+                }
+            }
+            _ => Err(Error::new(ErrorKind::TypeMismatch, format!(
+                "Cannot compare values of different types {:?} and {:?}",
+                *self, *other
+            ))),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Left and right operands of the matrices below, in row and column order
+    fn operands() -> [Value; 5] {
+        [
+            Value::Int(6),
+            Value::Number(1.5),
+            Value::String("2".to_string()),
+            Value::String("a".to_string()),
+            Value::Bool(true),
+        ]
+    }
+
+    type Matrix = [[Option<&'static str>; 5]; 5];
+
+    // Each cell is the Debug form of the result, None where the operation must
+    // fail with "Cannot <verb> <left> and <right>"
+    fn check_matrix(verb: &str, matrix: &Matrix, operation: fn(Value, Value) -> Result<Value, Error>) {
+        for (left, row) in operands().iter().zip(matrix.iter()) {
+            for (right, expected) in operands().iter().zip(row.iter()) {
+                let result = operation(left.clone(), right.clone());
+                match (expected, result) {
+                    (Some(expected), Ok(value)) => assert_eq!(format!("{:?}", value), *expected, "{:?} {} {:?}", left, verb, right),
+                    (None, Err(error)) => {
+                        assert_eq!(error.kind, ErrorKind::TypeMismatch);
+                        assert_eq!(error.message, format!("Cannot {} {} and {}", verb, left.describe(), right.describe()));
+                    }
+                    (_, result) => panic!("{:?} {} {:?} gave {:?}", left, verb, right, result),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn add_matrix() {
+        let matrix: Matrix = [
+            [Some("Int(12)"), Some("Number(7.5)"), Some("Number(8.0)"), None, None],
+            [Some("Number(7.5)"), Some("Number(3.0)"), Some("Number(3.5)"), None, None],
+            [Some("Number(8.0)"), Some("Number(3.5)"), Some("String(\"22\")"), Some("String(\"2a\")"), None],
+            [None, None, Some("String(\"a2\")"), Some("String(\"aa\")"), None],
+            [None, None, None, None, None],
+        ];
+        check_matrix("add", &matrix, |left, right| left + right);
+    }
+
+    #[test]
+    fn subtract_matrix() {
+        let matrix: Matrix = [
+            [Some("Int(0)"), Some("Number(4.5)"), Some("Number(4.0)"), None, None],
+            [Some("Number(-4.5)"), Some("Number(0.0)"), Some("Number(-0.5)"), None, None],
+            [Some("Number(-4.0)"), Some("Number(0.5)"), None, None, None],
+            [None, None, None, None, None],
+            [None, None, None, None, None],
+        ];
+        check_matrix("subtract", &matrix, |left, right| left - right);
+    }
+
+    #[test]
+    fn multiply_matrix() {
+        let matrix: Matrix = [
+            [Some("Int(36)"), Some("Number(9.0)"), Some("Number(12.0)"), None, None],
+            [Some("Number(9.0)"), Some("Number(2.25)"), Some("Number(3.0)"), None, None],
+            [Some("Number(12.0)"), Some("Number(3.0)"), None, None, None],
+            [None, None, None, None, None],
+            [None, None, None, None, None],
+        ];
+        check_matrix("multiply", &matrix, |left, right| left * right);
+    }
+
+    #[test]
+    fn divide_matrix() {
+        let matrix: Matrix = [
+            [Some("Number(1.0)"), Some("Number(4.0)"), Some("Number(3.0)"), None, None],
+            [Some("Number(0.25)"), Some("Number(1.0)"), Some("Number(0.75)"), None, None],
+            [Some("Number(0.3333333333333333)"), Some("Number(1.3333333333333333)"), None, None, None],
+            [None, None, None, None, None],
+            [None, None, None, None, None],
+        ];
+        check_matrix("divide", &matrix, |left, right| left / right);
+    }
+
+    #[test]
+    fn concatenate_matrix() {
+        let matrix: Matrix = [
+            [Some("String(\"66\")"), Some("String(\"61.5\")"), Some("String(\"62\")"), Some("String(\"6a\")"), None],
+            [Some("String(\"1.56\")"), Some("String(\"1.51.5\")"), Some("String(\"1.52\")"), Some("String(\"1.5a\")"), None],
+            [Some("String(\"26\")"), Some("String(\"21.5\")"), Some("String(\"22\")"), Some("String(\"2a\")"), None],
+            [Some("String(\"a6\")"), Some("String(\"a1.5\")"), Some("String(\"a2\")"), Some("String(\"aa\")"), None],
+            [None, None, None, None, None],
+        ];
+        check_matrix("concatenate", &matrix, Value::concat);
+    }
+
+    #[test]
+    fn exponentiate_matrix() {
+        let matrix: Matrix = [
+            [Some("Int(46656)"), Some("Number(14.696938456699069)"), Some("Number(36.0)"), None, None],
+            [Some("Number(11.390625)"), Some("Number(1.8371173070873836)"), Some("Number(2.25)"), None, None],
+            [Some("Number(64.0)"), Some("Number(2.8284271247461903)"), None, None, None],
+            [None, None, None, None, None],
+            [None, None, None, None, None],
+        ];
+        check_matrix("exponentiate", &matrix, Value::pow);
+    }
+
+    // false orders before true
+    #[test]
+    fn bool_ordering() {
+        // left, right, <, >, <=, >=
+        let cases = [
+            (false, false, false, false, true, true),
+            (false, true, true, false, true, false),
+            (true, false, false, true, false, true),
+            (true, true, false, false, true, true),
+        ];
+        for &(bool1, bool2, lt, gt, lteq, gteq) in cases.iter() {
+            let (left, right) = (Value::Bool(bool1), Value::Bool(bool2));
+            assert_eq!(left.lt(&right).unwrap(), lt, "{} < {}", bool1, bool2);
+            assert_eq!(left.gt(&right).unwrap(), gt, "{} > {}", bool1, bool2);
+            assert_eq!(left.lteq(&right).unwrap(), lteq, "{} <= {}", bool1, bool2);
+            assert_eq!(left.gteq(&right).unwrap(), gteq, "{} >= {}", bool1, bool2);
+        }
+    }
+
+    #[test]
+    fn value_is_lteq_and_gteq_itself() {
+        let values = [
+            Value::Int(2),
+            Value::Int(-7),
+            Value::Number(2.5),
+            Value::Number(-0.0),
+            Value::String("2".to_string()),
+            Value::String("abc".to_string()),
+            Value::String(String::new()),
+        ];
+        for value in values.iter() {
+            assert!(value.lteq(value).unwrap(), "{:?} <= itself", value);
+            assert!(value.gteq(value).unwrap(), "{:?} >= itself", value);
+        }
+    }
+
+    #[test]
+    fn numeric_string_compares_with_number() {
+        let two = Value::String("2".to_string());
+        assert!(two.gteq(&Value::Int(2)).unwrap());
+        assert!(two.lteq(&Value::Int(2)).unwrap());
+        assert!(Value::Int(2).gteq(&two).unwrap());
+        assert!(two.gteq(&Value::Number(1.5)).unwrap());
+        assert!(!two.lteq(&Value::Number(1.5)).unwrap());
+        assert!(!two.gteq(&Value::Number(f64::NAN)).unwrap());
+        assert_eq!(Value::String("a".to_string()).gteq(&Value::Int(2)).unwrap_err().kind, ErrorKind::TypeMismatch);
+    }
+
+    #[test]
+    fn number_formatting() {
+        let cases = [
+            (5.0, "5"),
+            (0.5, "0.5"),
+            (1.0 / 3.0, "0.333333333333333"),
+            (0.1 + 0.2, "0.3"),
+            (-0.0, "0"),
+            (123_456_789_012_345.0, "123456789012345"),
+            (1e15, "1E+15"),
+            (1e20, "1E+20"),
+            (1e300, "1E+300"),
+            (-2.5e100, "-2.5E+100"),
+            (0.0001, "0.0001"),
+            (0.00001, "1E-05"),
+            (1.5e-300, "1.5E-300"),
+        ];
+        for &(number, expected) in cases.iter() {
+            assert_eq!(format_number(number), expected, "{:e}", number);
         }
     }
 
-    pub fn lteq(&self, other: &Value) -> Result<bool, String> {
-        self.gt(other).map(|value| !value)
+    #[test]
+    fn number_formatting_to_digits() {
+        assert_eq!(format_number_to(1.0 / 3.0, 3), "0.333");
+        assert_eq!(format_number_to(1234.0, 3), "1.23E+03");
+        assert_eq!(format_number_to(999.9, 3), "1E+03");
+        assert_eq!(format_number_to(123.0, 3), "123");
+        assert_eq!(format_number_to(2.0, 1), "2");
     }
 
-    pub fn gteq(&self, other: &Value) -> Result<bool, String> {
-        self.lt(other).map(|value| !value)
+    #[test]
+    fn division_by_zero_is_its_own_kind() {
+        for divisor in [Value::Int(0), Value::Number(0.0), Value::String("0".to_string())].iter() {
+            let error = (Value::Int(1) / divisor.clone()).unwrap_err();
+            assert_eq!(error.kind, ErrorKind::DivisionByZero);
+        }
+    }
+
+    #[test]
+    fn zero_over_zero_is_division_by_zero() {
+        for zero in [Value::Int(0), Value::Number(0.0)].iter() {
+            let error = (zero.clone() / zero.clone()).unwrap_err();
+            assert_eq!(error.kind, ErrorKind::DivisionByZero);
+        }
     }
 }
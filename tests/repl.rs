@@ -0,0 +1,25 @@
+use std::{
+    io::Write,
+    process::{Command, Stdio},
+};
+
+// Feeds `session` to the REPL on stdin and returns what it printed
+fn repl(session: &str) -> (String, String) {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_yarxbi"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("interpreter should start");
+    child.stdin.take().unwrap().write_all(session.as_bytes()).unwrap();
+    let output = child.wait_with_output().unwrap();
+
+    (String::from_utf8(output.stdout).unwrap(), String::from_utf8(output.stderr).unwrap())
+}
+
+#[test]
+fn stop_inspect_and_cont() {
+    let (stdout, stderr) = repl("10 LET X = 1\n20 STOP\n30 PRINT \"after\"; X\nRUN\nPRINT X\nLET X = 5\nCONT\n");
+    assert_eq!(stderr, "");
+    assert!(stdout.contains("1\nafter5\n"), "unexpected output {:?}", stdout);
+}